@@ -0,0 +1,31 @@
+//! Measures the throughput of the SHA-256 hashing of the downloads. Run it with `cargo bench
+//! --bench sha256 --profile dev` to measure the hashing of `cargo run` builds, which is affected
+//! by the `profile.dev.package.sha2` override in `Cargo.toml`.
+
+use sha2::{Digest as _, Sha256};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Size of the buffer of `tokio::io::copy`, which writes the downloads to the hashing writer.
+const CHUNK_SIZE: usize = 8 * 1024;
+const TOTAL_SIZE: usize = 256 * 1024 * 1024;
+const RUNS: usize = 5;
+
+fn main() {
+    let chunk = vec![0x5a; CHUNK_SIZE];
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let mut sha256 = Sha256::new();
+        for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+            sha256.update(black_box(&chunk));
+        }
+        black_box(sha256.finalize());
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "hashed {} MiB in {best:.2?} ({:.0} MiB/s)",
+        TOTAL_SIZE / 1024 / 1024,
+        TOTAL_SIZE as f64 / 1024.0 / 1024.0 / best.as_secs_f64()
+    );
+}
@@ -0,0 +1,97 @@
+use crate::manifest::ArchiveFormat;
+use crate::utils::to_hex;
+use anyhow::{Context as _, Error, bail};
+use sha2::{Digest as _, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Check that the file at `path` is a valid archive of the expected format, by reading the
+/// first entry of the archive. This catches upstreams serving error pages instead of the file.
+///
+/// This uses blocking IO, and should be called from a blocking task.
+pub(crate) fn validate(path: &Path, format: ArchiveFormat) -> Result<(), Error> {
+    validate_inner(path, format)
+        .with_context(|| format!("downloaded content is not a valid {format}"))
+}
+
+fn validate_inner(path: &Path, format: ArchiveFormat) -> Result<(), Error> {
+    let file = File::open(path)?;
+    match format {
+        ArchiveFormat::TarGz => validate_tar(flate2::read::GzDecoder::new(file)),
+        ArchiveFormat::TarXz => validate_tar(xz2::read::XzDecoder::new(file)),
+        ArchiveFormat::Zip => {
+            let archive = zip::ZipArchive::new(file)?;
+            if archive.is_empty() {
+                bail!("the archive is empty");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn validate_tar(reader: impl Read) -> Result<(), Error> {
+    let mut archive = tar::Archive::new(reader);
+    match archive.entries()?.next() {
+        Some(entry) => {
+            entry?.header().path()?;
+            Ok(())
+        }
+        None => bail!("the archive is empty"),
+    }
+}
+
+/// Hashes of the regular files contained in a tar archive, keyed by their path. Comparing them
+/// pinpoints which members changed when the hash of the whole archive doesn't match. Returns
+/// `None` for zip archives.
+///
+/// This uses blocking IO, and should be called from a blocking task.
+pub(crate) fn member_hashes(
+    path: &Path,
+    format: ArchiveFormat,
+) -> Result<Option<BTreeMap<String, String>>, Error> {
+    let file = File::open(path)?;
+    match format {
+        ArchiveFormat::TarGz => tar_member_hashes(flate2::read::GzDecoder::new(file)).map(Some),
+        ArchiveFormat::TarXz => tar_member_hashes(xz2::read::XzDecoder::new(file)).map(Some),
+        ArchiveFormat::Zip => Ok(None),
+    }
+}
+
+fn tar_member_hashes(reader: impl Read) -> Result<BTreeMap<String, String>, Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut hashes = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut sha256 = Sha256::new();
+        std::io::copy(&mut entry, &mut sha256)?;
+        hashes.insert(path, to_hex(&sha256));
+    }
+    Ok(hashes)
+}
+
+/// Describe the members added, removed or changed between the `recorded` and `current` hashes.
+pub(crate) fn diff_members(
+    recorded: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (path, hash) in recorded {
+        match current.get(path) {
+            None => changes.push(format!("removed {path}")),
+            Some(current) if current != hash => changes.push(format!("changed {path}")),
+            Some(_) => {}
+        }
+    }
+    for path in current.keys() {
+        if !recorded.contains_key(path) {
+            changes.push(format!("added {path}"));
+        }
+    }
+    changes
+}
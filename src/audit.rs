@@ -0,0 +1,73 @@
+use crate::storage::{FileStatus, MissingPart};
+use crate::utils::OutputFormat;
+use anyhow::Error;
+use serde::Serialize;
+use std::fmt::Write as _;
+
+/// Number of mirrored files in each state on the CDN.
+#[derive(Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Audit {
+    pub(crate) total: usize,
+    pub(crate) present: usize,
+    pub(crate) missing: usize,
+    /// Legacy files uploaded without their `.sha256` file.
+    pub(crate) legacy: usize,
+    /// Files with a `.sha256` file but no object.
+    pub(crate) missing_objects: usize,
+    /// Files without their `.sha256` file, or with a corrupt one.
+    pub(crate) inconsistent: usize,
+    /// Files whose status couldn't be checked.
+    pub(crate) errors: usize,
+}
+
+impl Audit {
+    pub(crate) fn record(&mut self, status: &Result<FileStatus, Error>, legacy: bool) {
+        self.total += 1;
+        match status {
+            Ok(FileStatus::Present { .. }) => self.present += 1,
+            Ok(FileStatus::Missing) => self.missing += 1,
+            Ok(FileStatus::Inconsistent {
+                missing: MissingPart::Sidecar,
+            }) if legacy => self.legacy += 1,
+            Ok(FileStatus::Inconsistent {
+                missing: MissingPart::Object { .. },
+            }) => self.missing_objects += 1,
+            Ok(FileStatus::Inconsistent { .. } | FileStatus::CorruptSidecar { .. }) => {
+                self.inconsistent += 1
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    /// Whether the audit found files that can't be downloaded from the CDN, or couldn't check
+    /// some of them.
+    pub(crate) fn failed(&self) -> bool {
+        self.missing > 0 || self.missing_objects > 0 || self.errors > 0
+    }
+
+    pub(crate) fn render(&self, format: OutputFormat) -> Result<String, Error> {
+        match format {
+            OutputFormat::Json => {
+                let mut output = serde_json::to_string_pretty(self)?;
+                output.push('\n');
+                Ok(output)
+            }
+            OutputFormat::Text => {
+                let mut output = String::new();
+                for (label, count) in [
+                    ("total", self.total),
+                    ("present", self.present),
+                    ("missing", self.missing),
+                    ("legacy", self.legacy),
+                    ("missing-objects", self.missing_objects),
+                    ("inconsistent", self.inconsistent),
+                    ("errors", self.errors),
+                ] {
+                    writeln!(output, "{label:<15}  {count}").unwrap();
+                }
+                Ok(output)
+            }
+        }
+    }
+}
@@ -0,0 +1,1980 @@
+//! Command line interface of the tool.
+
+use crate::archive;
+use crate::audit::Audit;
+use crate::config::Config;
+use crate::diff;
+use crate::doctor::Report;
+use crate::downloader::{Downloader, EMPTY_SHA256, format_size, get_local_file_hash};
+use crate::health::{self, Health, Probe};
+use crate::http::HttpArgs;
+use crate::index;
+use crate::listing::{self, ListingFormat};
+use crate::manifest::{
+    self, Compression, LoadOptions, ManifestFileManaged, MirrorFile, Source, check_format,
+    find_duplicates, load_manifest_source, load_manifests, rename_entry,
+};
+use crate::mirror::{Mirror, MirrorConfig, Progress};
+use crate::run_report::RunReport;
+use crate::storage::{
+    self, CdnReader, DEFAULT_S3_RETRIES, DEFAULT_STATUS_RETRIES, FileStatus, MissingPart,
+    S3Storage, Storage,
+};
+use crate::utils::{self, LogWriter, OutputFormat, PROGRESS_TARGET, is_sha256_hex, to_hex};
+use anyhow::{Context as _, Error, anyhow, bail};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory as _, FromArgMatches as _, Parser, Subcommand};
+use rand::seq::SliceRandom as _;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::IsTerminal as _;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Exit code of the `upload` command when it exceeds its `--deadline`, matching `timeout(1)`.
+const DEADLINE_EXIT_CODE: i32 = 124;
+
+/// Version printed by `--version`, including the git revision and the date of the build.
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CI_MIRRORS_GIT_SHA"),
+    " ",
+    env!("CI_MIRRORS_BUILD_DATE"),
+    ")"
+);
+
+/// Manage mirrored files on rust-lang CDN.
+#[derive(Debug, Parser)]
+#[command(version = LONG_VERSION)]
+pub(crate) struct Cli {
+    /// Minimum level of the logs to show. More granular filters can be set with `RUST_LOG`.
+    #[arg(long, global = true, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Configuration file containing the defaults of the `upload` flags. Defaults to
+    /// `mirror.toml` in the current directory, if it exists.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Upload files to the CDN and check that the local files are consistent.
+    Upload(UploadArgs),
+    /// Add a new mirrored file entry.
+    AddFile(AddFileArgs),
+    /// Check that the S3 buckets and the CDN are reachable with the current credentials.
+    Doctor(DoctorArgs),
+    /// Download the files from their sources again, checking that their hash didn't change.
+    CheckUpstream(CheckUpstreamArgs),
+    /// Generate a JSON index of all the mirrored files.
+    GenerateIndex(GenerateIndexArgs),
+    /// Move a mirrored file to a different path on the CDN, without downloading it again.
+    Rename(RenameArgs),
+    /// Write the missing `.sha256` files of the legacy entries, after checking that the uploaded
+    /// objects match their hash, so that they are tracked like managed files.
+    Backfill(BackfillArgs),
+    /// Print the entries to add to mirror all the files of an upstream directory listing.
+    ExpandIndex(ExpandIndexArgs),
+    /// Add entries for the files listed in an upstream checksums file, without downloading them.
+    ImportSums(ImportSumsArgs),
+    /// Check that the manifests are sorted and formatted canonically.
+    FormatCheck(FormatCheckArgs),
+    /// Check that the manifests are valid, without accessing the network.
+    Validate(ValidateArgs),
+    /// Show the entries added, removed or changed between two directories of manifests.
+    Diff(DiffArgs),
+    /// Check whether the upstream of each mirrored file and the CDN are reachable, using HEAD
+    /// requests.
+    HealthCheck(HealthCheckArgs),
+    /// Check which mirrored files are present on the CDN, printing how many are present, missing,
+    /// legacy or inconsistent.
+    Audit(AuditArgs),
+    /// Print the S3 keys of the object and of the `.sha256` file of each mirrored file, without
+    /// accessing the network.
+    Keys(KeysArgs),
+    /// Print the JSON Schema of the manifests, describing the fields of each kind of entry.
+    Describe,
+    /// Print the version of the tool as JSON, including the git revision and the date of the
+    /// build, for CI to record which version ran.
+    Version,
+}
+
+/// Output of the `version` command.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    build_date: &'static str,
+}
+
+#[derive(Debug, Parser)]
+struct UploadArgs {
+    /// Path to the manifest to synchronize.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Only load this manifest file instead of the whole directory. This is meant to speed up
+    /// local development, and shouldn't be used in CI.
+    #[arg(long, conflicts_with = "manifests_dir")]
+    manifest_file: Option<PathBuf>,
+
+    /// Only check which changes are needed (no credentials required).
+    #[arg(long)]
+    skip_upload: bool,
+
+    /// Automatically add missing `rename-from` fields to the manifests, and trim whitespace
+    /// around the `name` and `sha256` fields.
+    #[arg(long)]
+    fix: bool,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Only check and upload the files whose name matches this glob. This is meant to speed up
+    /// local development, and shouldn't be used in CI. Manifests are still fully validated.
+    #[arg(long)]
+    only: Option<glob::Pattern>,
+
+    /// Skip checking and uploading the files whose name matches this glob, for example when
+    /// their upstream is temporarily unavailable. Can be repeated. Manifests are still fully
+    /// validated.
+    #[arg(long)]
+    exclude: Vec<glob::Pattern>,
+
+    /// Only check and upload the files defined in the manifests changed since this git
+    /// revision. Manifests are still fully validated. All the files are processed if the changes
+    /// can't be determined.
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Base URL of the CDN where mirrored files are served.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[command(flatten)]
+    s3: S3Args,
+
+    /// Number of times to retry checking the status of a file when it fails with a transient
+    /// error, such as a timeout, a server error or throttling, waiting longer after each attempt.
+    #[arg(long, default_value_t = DEFAULT_STATUS_RETRIES)]
+    status_retries: u32,
+
+    /// Upload the files matched by `--only` even if they already exist, replacing them. This is
+    /// an escape hatch to repair corrupted objects, and requires changing the IAM policy.
+    #[arg(long, requires = "only")]
+    allow_overwrite: bool,
+
+    /// Also upload a `.sri` file next to each newly uploaded file, containing its Subresource
+    /// Integrity hash.
+    #[arg(long)]
+    write_sri: bool,
+
+    /// Tag the uploaded objects with `managed`, with `tool` (the first segment of their name) and
+    /// with the `tags` of their entry, for the lifecycle rules and the cost reports. This requires
+    /// the `s3:PutObjectTagging` permission.
+    #[arg(long)]
+    tag_objects: bool,
+
+    /// Also check that the ETag of each uploaded object matches the local file, and read back
+    /// the uploaded text files and the tags of the objects (which requires the
+    /// `s3:GetObjectTagging` permission with --tag-objects). The size of the objects is always
+    /// checked.
+    #[arg(long)]
+    verify_after_upload: bool,
+
+    /// Record the hashes of the uploaded objects in their metadata, and check whether the files
+    /// are present with HEAD requests of the object and its `.sha256` file when the ETag of the
+    /// object matches the recorded MD5 hash, instead of reading the `.sha256` file. Files
+    /// uploaded without the hashes are still checked by reading their `.sha256` file.
+    #[arg(long)]
+    etag_status: bool,
+
+    /// Don't check that there is enough disk space to download the files before downloading
+    /// them.
+    #[arg(long)]
+    no_space_check: bool,
+
+    /// Store the downloaded files compressed with zstd until they are uploaded, to reduce the disk
+    /// space needed when many large files are mirrored. This costs CPU time, as each file is
+    /// compressed after being downloaded and decompressed again before being uploaded.
+    #[arg(long)]
+    compress_temp: bool,
+
+    /// Download the files to a temporary directory created in this directory, instead of the
+    /// system temporary directory, for example to use a larger volume.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Index written by `generate-index`, for example from a previous revision of the manifests,
+    /// used to find the files already uploaded under another path with the same content. Instead
+    /// of being uploaded again, they are copied within the buckets, and the bytes avoided are
+    /// reported. The files are still downloaded and verified first, so only the uploads are
+    /// saved. Files are always uploaded with --allow-overwrite.
+    #[arg(long)]
+    dedup_index: Option<PathBuf>,
+
+    /// Also check that the files already uploaded were mirrored from the upstream file named in
+    /// the manifests, and that their `rename-from` matches their `source`, to catch manual edits
+    /// of either. Only files uploaded with their source recorded can be checked.
+    #[arg(long)]
+    verify_present: bool,
+
+    /// Also upload a `.members.json` file next to each uploaded tar archive, with the hashes of
+    /// the files it contains. This lets `check-upstream --compare-members` report which files
+    /// changed inside the archive.
+    #[arg(long)]
+    member_hashes: bool,
+
+    /// Stop downloading files once this many bytes were downloaded in total, to guard against
+    /// changes adding unexpectedly large files. The files not downloaded are reported as errors.
+    #[arg(long)]
+    max_download_bytes: Option<u64>,
+
+    /// Stop at the first error found while validating the manifests, checking the status of the
+    /// files or downloading them, instead of reporting all of them at the end. This is meant to
+    /// speed up local development, and shouldn't be used in CI.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Upload the files downloaded successfully even if others failed to download, still exiting
+    /// with an error afterwards. Errors found while checking the status of the files still
+    /// prevent any upload.
+    #[arg(long, conflicts_with = "fail_fast")]
+    partial: bool,
+
+    /// Only check that the upstreams of the files missing from the CDN are reachable, with HEAD
+    /// requests, instead of downloading them and checking their hashes. This is a fast check
+    /// for PRs, and requires --skip-upload.
+    #[arg(long, requires = "skip_upload")]
+    no_download: bool,
+
+    /// Check that the credentials can upload to the buckets before downloading anything, by
+    /// writing a scratch object to each bucket (deleted afterwards if the policy allows it).
+    #[arg(long, conflicts_with = "skip_upload")]
+    check_credentials: bool,
+
+    /// Ask for confirmation before uploading the files, after showing where they will be
+    /// uploaded. This requires stdin to be a terminal.
+    #[arg(long, conflicts_with = "skip_upload")]
+    interactive: bool,
+
+    /// Write a JSON report of the run to this file, including the errors. The report is written
+    /// even when the run fails.
+    #[arg(long)]
+    report_file: Option<PathBuf>,
+
+    /// Abort the run after this many seconds, reporting how far it got and exiting with code 124.
+    /// The uploads in progress are cancelled before their `.sha256` file is written, so the next
+    /// run uploads them again.
+    #[arg(long, value_name = "SECONDS")]
+    deadline: Option<u64>,
+
+    /// Store all the files under this path on the CDN, for example `staging` to run a staging
+    /// mirror with the same manifests.
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// Cache which files are present on the CDN in this file, to skip checking them again in
+    /// the next runs. Only used with --skip-upload.
+    #[arg(long)]
+    status_cache: Option<PathBuf>,
+
+    #[arg(short, long, default_value = "100")]
+    jobs: usize,
+
+    /// Don't log the progress of each file nor show the progress bars of the downloads. Errors and
+    /// the final summary are still shown.
+    #[arg(short, long)]
+    quiet: bool,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct AddFileArgs {
+    /// URL that should be mirrored.
+    url: Url,
+    /// Path under which the file should be available on the CDN.
+    #[arg(long)]
+    path: String,
+    /// TOML file into which should the mirrored entry be added.
+    #[arg(long, required_unless_present = "auto_toml")]
+    toml_file: Option<PathBuf>,
+    /// Add the entry to `<manifests-dir>/<first segment of the path>.toml` instead of passing
+    /// `--toml-file`, creating it if needed.
+    #[arg(long, conflicts_with = "toml_file")]
+    auto_toml: bool,
+    /// Directory containing the manifests, used with `--auto-toml`.
+    #[arg(long, default_value = "files/")]
+    manifests_dir: PathBuf,
+    /// License of the file.
+    #[arg(long)]
+    license: Option<String>,
+    /// Compression format of the file, to record the hash of its decompressed content instead.
+    #[arg(long)]
+    decompress: Option<Compression>,
+    /// SHA-256 of the file, for example from the checksums published upstream. The file is not
+    /// downloaded when this is provided.
+    #[arg(long)]
+    sha256: Option<String>,
+    /// Download the file anyway, checking that it matches the hash passed to `--sha256`.
+    #[arg(long, requires = "sha256")]
+    verify: bool,
+    /// Compute the hash of this local copy of the file instead of downloading it. The URL is
+    /// still recorded as the source of the file.
+    #[arg(long)]
+    from_file: Option<PathBuf>,
+    /// Add the entry even if the TOML file already contains an entry with the same path, URL or
+    /// hash, or if the downloaded file is empty.
+    #[arg(long)]
+    force: bool,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+/// Checks applied to the manifests when loading them.
+#[derive(Debug, Parser)]
+struct LoadArgs {
+    /// Fail if any managed entry has an empty `license`.
+    #[arg(long)]
+    require_license: bool,
+
+    /// Fail if any entry is downloaded from a URL or git repository over plain HTTP, rather than
+    /// only warning about it.
+    #[arg(long)]
+    deny_insecure_urls: bool,
+
+    /// Also fail if the CDN paths of two entries only differ by case, for CDNs ignoring it.
+    /// Paths only differing by a trailing slash are always reported.
+    #[arg(long)]
+    case_insensitive_paths: bool,
+
+    /// Don't warn when the manifests directory doesn't contain any manifest.
+    #[arg(long)]
+    allow_empty: bool,
+
+    /// Fail if a managed entry is named with this extension (like `exe`), unless it's marked with
+    /// `allow-extension = true`. Can be repeated.
+    #[arg(long = "denied-extension")]
+    denied_extensions: Vec<String>,
+
+    /// Fail if a managed entry is not named with one of these extensions, unless it's marked with
+    /// `allow-extension = true`. Can be repeated. All extensions are allowed when not passed.
+    #[arg(long = "allowed-extension")]
+    allowed_extensions: Vec<String>,
+}
+
+impl LoadArgs {
+    /// Options to load the manifests with, rewriting them to fix the errors when `fix` is set.
+    fn options(&self, fix: bool) -> LoadOptions {
+        LoadOptions {
+            fix,
+            require_license: self.require_license,
+            deny_insecure_urls: self.deny_insecure_urls,
+            denied_extensions: self.denied_extensions.clone(),
+            allowed_extensions: self.allowed_extensions.clone(),
+            case_insensitive_paths: self.case_insensitive_paths,
+            allow_empty: self.allow_empty,
+        }
+    }
+}
+
+/// Location of the S3 buckets the files are stored in.
+#[derive(Debug, Parser)]
+struct S3Args {
+    /// Name of the S3 bucket containing the files. Can be repeated to replicate the files to
+    /// multiple buckets, using `name:region` for buckets outside of the default region.
+    #[arg(long = "s3-bucket", default_value = "rust-lang-ci-mirrors")]
+    s3_buckets: Vec<String>,
+
+    /// Send the S3 requests to this endpoint instead of AWS, for example to test against a local
+    /// MinIO server. Path-style addressing is used when set.
+    #[arg(long)]
+    s3_endpoint_url: Option<String>,
+
+    /// Number of times to retry S3 requests failing with transient errors, such as throttling.
+    #[arg(long, default_value_t = DEFAULT_S3_RETRIES)]
+    s3_retries: u32,
+}
+
+#[derive(Debug, Parser)]
+struct DoctorArgs {
+    /// Base URL of the CDN where mirrored files are served.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[command(flatten)]
+    s3: S3Args,
+
+    /// Path of a file known to be present in the S3 buckets.
+    #[arg(long, default_value = "bors-r-plus.png")]
+    known_file: String,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct CheckUpstreamArgs {
+    /// Path to the manifests to check.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Only check this many randomly chosen files, to reduce the bandwidth used.
+    #[arg(long)]
+    sample: Option<usize>,
+
+    /// When the upstream of a tar archive changed, report which files inside it changed, by
+    /// comparing them with the hashes recorded by `upload --member-hashes`. This stores the
+    /// archives on disk while checking them.
+    #[arg(long)]
+    compare_members: bool,
+
+    /// Base URL of the CDN where mirrored files are served.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[arg(short, long, default_value = "100")]
+    jobs: usize,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct GenerateIndexArgs {
+    /// Path to the manifests to include in the index.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Path where the index will be written.
+    #[arg(long, default_value = "index.json")]
+    output: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+struct BackfillArgs {
+    /// Path to the manifests containing the legacy entries.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Only report the legacy entries missing their `.sha256` file, without downloading them.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Base URL of the CDN the objects are downloaded from to check their hash.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[command(flatten)]
+    s3: S3Args,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct RenameArgs {
+    /// Current path of the file on the CDN.
+    old_name: String,
+    /// New path of the file on the CDN.
+    new_name: String,
+
+    /// Path to the manifests containing the file.
+    #[arg(long, default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    #[command(flatten)]
+    s3: S3Args,
+
+    /// Delete the objects at the old path once they are copied.
+    #[arg(long)]
+    delete_old: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ExpandIndexArgs {
+    /// URL of the upstream directory listing.
+    url: Url,
+    /// Directory under which the files should be available on the CDN.
+    path_prefix: String,
+    /// License of the files.
+    #[arg(long)]
+    license: Option<String>,
+    /// Format of the directory listing.
+    #[arg(long, default_value = "autoindex")]
+    format: ListingFormat,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct ImportSumsArgs {
+    /// URL of the checksums file, in the format of `sha256sum` (like `SHA256SUMS` files).
+    sums_url: Url,
+    /// URL of the directory containing the files listed in the checksums file.
+    base_url: Url,
+    /// TOML file into which the entries should be added.
+    toml_file: PathBuf,
+    /// Directory under which the files should be available on the CDN.
+    #[arg(long)]
+    path_prefix: String,
+    /// Only add the files whose name matches this glob.
+    #[arg(long)]
+    include: Option<glob::Pattern>,
+    /// License of the files.
+    #[arg(long)]
+    license: Option<String>,
+    /// Add the entries even if the TOML file already contains an entry with the same path, URL
+    /// or hash, instead of skipping them.
+    #[arg(long)]
+    force: bool,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct FormatCheckArgs {
+    /// Path to the manifests to check.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Rewrite the manifests that are not formatted canonically.
+    #[arg(long)]
+    write: bool,
+}
+
+#[derive(Debug, Parser)]
+struct DiffArgs {
+    /// Path to the manifests before the changes.
+    before: PathBuf,
+
+    /// Path to the manifests after the changes.
+    after: PathBuf,
+
+    /// Format of the differences printed to stdout.
+    #[arg(long, default_value = "text")]
+    output: OutputFormat,
+}
+
+#[derive(Debug, Parser)]
+struct HealthCheckArgs {
+    /// Path to the manifests to check.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Base URL of the CDN where mirrored files are served.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[arg(short, long, default_value = "100")]
+    jobs: usize,
+
+    /// Format of the report printed to stdout.
+    #[arg(long, default_value = "text")]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct AuditArgs {
+    /// Path to the manifests to check.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Only print the counts, without logging each file that is not present.
+    #[arg(long)]
+    summary_only: bool,
+
+    /// Base URL of the CDN where mirrored files are served.
+    #[arg(long, default_value = "https://ci-mirrors.rust-lang.org")]
+    cdn_url: String,
+
+    #[arg(short, long, default_value = "100")]
+    jobs: usize,
+
+    /// Format of the counts printed to stdout.
+    #[arg(long, default_value = "text")]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Debug, Parser)]
+struct KeysArgs {
+    /// Path to the manifests to read.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    /// Path under which all the files are stored, as passed to `upload --prefix`.
+    #[arg(long)]
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+struct ValidateArgs {
+    /// Path to the manifests to validate, or `-` to read a single manifest from stdin.
+    #[arg(default_value = "files/")]
+    manifests_dir: PathBuf,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Print the errors to stdout as JSON, with the file and line of the entries involved.
+    #[arg(long)]
+    json_errors: bool,
+}
+
+impl Cli {
+    /// Parse the command line, taking the values of the flags not passed on it from the
+    /// configuration file.
+    pub(crate) fn parse_with_config() -> Result<Self, Error> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        let config = Config::load(cli.config.as_deref())?;
+        if let Command::Upload(args) = &mut cli.command
+            && let Some(("upload", matches)) = matches.subcommand()
+        {
+            apply_config(args, matches, config);
+        }
+        Ok(cli)
+    }
+}
+
+/// Replace the values of the flags not passed explicitly with the ones in the configuration.
+fn apply_config(args: &mut UploadArgs, matches: &ArgMatches, config: Config) {
+    let default = |id: &str| {
+        !matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    fn set<T>(target: &mut T, value: Option<T>, default: bool) {
+        if let Some(value) = value
+            && default
+        {
+            *target = value;
+        }
+    }
+
+    set(&mut args.cdn_url, config.cdn_url, default("cdn_url"));
+    set(
+        &mut args.s3.s3_buckets,
+        config.s3_buckets,
+        default("s3_buckets"),
+    );
+    set(
+        &mut args.s3.s3_endpoint_url,
+        config.s3_endpoint_url.map(Some),
+        default("s3_endpoint_url"),
+    );
+    set(
+        &mut args.s3.s3_retries,
+        config.s3_retries,
+        default("s3_retries"),
+    );
+    set(
+        &mut args.status_retries,
+        config.status_retries,
+        default("status_retries"),
+    );
+    set(&mut args.jobs, config.jobs, default("jobs"));
+    set(&mut args.prefix, config.prefix.map(Some), default("prefix"));
+    set(
+        &mut args.status_cache,
+        config.status_cache.map(Some),
+        default("status_cache"),
+    );
+    set(
+        &mut args.dedup_index,
+        config.dedup_index.map(Some),
+        default("dedup_index"),
+    );
+    args.write_sri |= config.write_sri;
+    args.tag_objects |= config.tag_objects;
+    args.verify_after_upload |= config.verify_after_upload;
+    args.etag_status |= config.etag_status;
+    args.load.require_license |= config.require_license;
+    args.load.deny_insecure_urls |= config.deny_insecure_urls;
+    args.load.case_insensitive_paths |= config.case_insensitive_paths;
+    set(
+        &mut args.load.denied_extensions,
+        config.denied_extensions,
+        default("denied_extensions"),
+    );
+    set(
+        &mut args.load.allowed_extensions,
+        config.allowed_extensions,
+        default("allowed_extensions"),
+    );
+    args.no_space_check |= config.no_space_check;
+    args.compress_temp |= config.compress_temp;
+    set(
+        &mut args.cache_dir,
+        config.cache_dir.map(Some),
+        default("cache_dir"),
+    );
+    set(
+        &mut args.max_download_bytes,
+        config.max_download_bytes.map(Some),
+        default("max_download_bytes"),
+    );
+    set(
+        &mut args.http.http_timeout,
+        config.http_timeout,
+        default("http_timeout"),
+    );
+    set(
+        &mut args.http.http_connect_timeout,
+        config.http_connect_timeout,
+        default("http_connect_timeout"),
+    );
+    set(
+        &mut args.http.min_download_speed,
+        config.min_download_speed.map(Some),
+        default("min_download_speed"),
+    );
+    set(
+        &mut args.http.user_agent,
+        config.user_agent,
+        default("user_agent"),
+    );
+    set(
+        &mut args.http.per_host_jobs,
+        config.per_host_jobs,
+        default("per_host_jobs"),
+    );
+    set(
+        &mut args.http.allow_s3_source_buckets,
+        config.allow_s3_source_buckets,
+        default("allow_s3_source_buckets"),
+    );
+    set(
+        &mut args.http.max_bandwidth,
+        config.max_bandwidth.map(Some),
+        default("max_bandwidth"),
+    );
+}
+
+/// Run the command parsed from the command line. This also sets up logging.
+pub(crate) async fn run(args: Cli) -> Result<(), Error> {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(args.log_level.into())
+        .from_env_lossy();
+    let quiet = matches!(&args.command, Command::Upload(upload) if upload.quiet);
+    if quiet {
+        filter = filter.add_directive(format!("{PROGRESS_TARGET}=off").parse()?);
+    }
+    if !quiet && std::io::stderr().is_terminal() {
+        utils::enable_progress_bars();
+    }
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| LogWriter)
+        .init();
+
+    match args.command {
+        Command::Upload(args) => {
+            upload(args).await?;
+        }
+        Command::AddFile(args) => {
+            add_file(args).await?;
+        }
+        Command::Doctor(args) => {
+            doctor(args).await?;
+        }
+        Command::CheckUpstream(args) => {
+            check_upstream(args).await?;
+        }
+        Command::GenerateIndex(args) => {
+            generate_index(args)?;
+        }
+        Command::Rename(args) => {
+            rename(args).await?;
+        }
+        Command::Backfill(args) => {
+            backfill(args).await?;
+        }
+        Command::ExpandIndex(args) => {
+            expand_index(args).await?;
+        }
+        Command::ImportSums(args) => {
+            import_sums(args).await?;
+        }
+        Command::FormatCheck(args) => {
+            format_check(args)?;
+        }
+        Command::Validate(args) => {
+            validate(args)?;
+        }
+        Command::Diff(args) => {
+            diff(args)?;
+        }
+        Command::HealthCheck(args) => {
+            health_check(args).await?;
+        }
+        Command::Audit(args) => {
+            audit(args).await?;
+        }
+        Command::Keys(args) => {
+            keys(args)?;
+        }
+        Command::Describe => {
+            println!("{}", manifest::schema()?);
+        }
+        Command::Version => {
+            let version = Version {
+                version: env!("CARGO_PKG_VERSION"),
+                git_sha: env!("CI_MIRRORS_GIT_SHA"),
+                build_date: env!("CI_MIRRORS_BUILD_DATE"),
+            };
+            println!("{}", serde_json::to_string_pretty(&version)?);
+        }
+    }
+
+    Ok(())
+}
+
+async fn upload(args: UploadArgs) -> anyhow::Result<()> {
+    let report_file = args.report_file.clone();
+    let deadline = args.deadline;
+    let mut report = RunReport::default();
+    let progress = Arc::new(Progress::default());
+    let run = run_upload(args, &mut report, progress.clone());
+    let mut timed_out = false;
+    let result = match deadline {
+        // Dropping the run on timeout cancels all the requests in progress.
+        Some(seconds) => match tokio::time::timeout(Duration::from_secs(seconds), run).await {
+            Ok(result) => result,
+            Err(_) => {
+                timed_out = true;
+                Err(anyhow!(
+                    "the run exceeded its deadline of {seconds} seconds while {progress}"
+                ))
+            }
+        },
+        None => run.await,
+    };
+    if let Err(err) = &result {
+        report.errors.push(format!("{err:?}"));
+    }
+    report.success = report.errors.is_empty();
+    if let Some(path) = report_file {
+        report.write(&path)?;
+    }
+    if timed_out && let Err(err) = &result {
+        error!("{err}");
+        std::process::exit(DEADLINE_EXIT_CODE);
+    }
+    result?;
+    if !report.success {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Run the `upload` command, recording its results in `report`. The command failed if any errors
+/// were recorded.
+async fn run_upload(
+    args: UploadArgs,
+    report: &mut RunReport,
+    progress: Arc<Progress>,
+) -> anyhow::Result<()> {
+    // Fail before the downloads rather than when asking for confirmation.
+    if args.interactive && !std::io::stdin().is_terminal() {
+        bail!("--interactive requires stdin to be a terminal");
+    }
+
+    let mirror = Mirror::new(MirrorConfig {
+        cdn_url: args.cdn_url,
+        s3_buckets: args.s3.s3_buckets,
+        s3_endpoint_url: args.s3.s3_endpoint_url,
+        s3_retries: args.s3.s3_retries,
+        status_retries: args.status_retries,
+        read_only: args.skip_upload,
+        write_sri: args.write_sri,
+        tag_objects: args.tag_objects,
+        verify_after_upload: args.verify_after_upload,
+        etag_status: args.etag_status,
+        jobs: args.jobs,
+        space_check: !args.no_space_check,
+        compress_temp: args.compress_temp,
+        cache_dir: args.cache_dir,
+        dedup_index: args.dedup_index,
+        verify_present: args.verify_present,
+        member_hashes: args.member_hashes,
+        max_download_bytes: args.max_download_bytes,
+        fail_fast: args.fail_fast,
+        no_download: args.no_download,
+        prefix: args.prefix,
+        status_cache: args.status_cache,
+        progress,
+        http: args.http,
+    })
+    .await?;
+
+    if args.check_credentials {
+        mirror
+            .check_credentials()
+            .await
+            .context("the credentials can't upload files")?;
+        info!("the credentials can upload files");
+    }
+
+    let options = args.load.options(args.fix);
+    let load_from = args.manifest_file.as_ref().unwrap_or(&args.manifests_dir);
+    let (mut files, errors) = mirror.load_manifests(load_from, &options)?;
+    let mut errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+    skip_disabled(&mut files);
+    if let Some(only) = &args.only {
+        files.retain(|file| only.matches(&file.name));
+    }
+    if let Some(since) = &args.since
+        && let Some(changed) = changed_manifests(load_from, since)
+    {
+        let mut names = HashSet::new();
+        for manifest in changed {
+            // Errors were already reported while loading all the manifests.
+            let (changed_files, _) = load_manifests(&manifest, &LoadOptions::default())?;
+            names.extend(changed_files.into_iter().map(|file| file.name));
+        }
+        files.retain(|file| names.contains(&file.name));
+        info!(
+            "{} file(s) are defined in manifests changed since {since}",
+            files.len()
+        );
+    }
+    if !args.exclude.is_empty() {
+        let before = files.len();
+        files.retain(|file| {
+            !args
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches(&file.name))
+        });
+        info!(
+            "excluded {} file(s) matching --exclude",
+            before - files.len()
+        );
+    }
+
+    if args.fail_fast && !errors.is_empty() {
+        files.clear();
+    }
+
+    // Collect all errors that happen during the check phase and show them at the end. This way, if
+    // there are multiple errors in CI users won't have to retry the build multiple times.
+    let mut plan = mirror.check_status(files, args.allow_overwrite).await?;
+    errors.extend(plan.errors.drain(..).map(|error| error.to_string()));
+    // With --partial, the failed downloads are reported without preventing the other files from
+    // being uploaded.
+    let download_errors = plan
+        .download_errors
+        .drain(..)
+        .map(|error| error.to_string());
+    let download_errors = if args.partial {
+        download_errors.collect()
+    } else {
+        errors.extend(download_errors);
+        Vec::new()
+    };
+    let partial = !download_errors.is_empty();
+
+    let to_upload = plan.files().count() + plan.unverified.len();
+    let (checked, present, legacy) = (plan.checked, plan.present, plan.legacy);
+    report.checked = checked;
+    report.present = present;
+    report.legacy = legacy;
+    report.to_upload = plan
+        .files()
+        .map(|file| file.name.clone())
+        .chain(plan.unverified.iter().cloned())
+        .collect();
+    report.not_downloaded = plan.not_downloaded.clone();
+    let not_downloaded = plan.not_downloaded.len();
+    let summary = |uploaded: &str| {
+        let failed = if partial {
+            format!(", {not_downloaded} failed to download")
+        } else {
+            String::new()
+        };
+        info!(
+            "checked {checked} files, {uploaded}, skipped {present} present, {legacy} legacy{failed}"
+        );
+    };
+
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in &errors {
+            error!("{error}");
+        }
+        summary("uploaded 0");
+        report.errors = errors;
+        return Ok(());
+    }
+    if partial {
+        error!(
+            "{not_downloaded} file(s) were not downloaded, uploading the other files due to \
+             --partial"
+        );
+        for error in &download_errors {
+            error!("{error}");
+        }
+        warn!("not uploading {}", plan.not_downloaded.join(", "));
+        report.errors = download_errors;
+    }
+    if !plan.is_empty() {
+        let mut total = 0;
+        info!("upload plan:");
+        for (key, size) in mirror.destinations(&plan) {
+            info!("  {key} ({})", format_size(size as usize));
+            total += size;
+        }
+        info!(
+            "{to_upload} file(s), {} in total",
+            format_size(total as usize)
+        );
+    }
+
+    if !plan.unverified.is_empty() {
+        info!(
+            "{} file(s) to upload were not downloaded due to --no-download: {}",
+            plan.unverified.len(),
+            plan.unverified.join(", ")
+        );
+    }
+    if plan.is_empty() && plan.unverified.is_empty() && !partial {
+        info!("everything is up to date!");
+        summary("uploaded 0");
+        return Ok(());
+    } else if args.skip_upload {
+        info!("skipping upload due to --skip-upload");
+        summary(&format!("{to_upload} to upload"));
+        return Ok(());
+    }
+
+    if args.interactive && !confirm("proceed with the upload?").await? {
+        info!("upload cancelled");
+        summary("uploaded 0");
+        return Ok(());
+    }
+
+    let errors = mirror.upload(plan).await;
+    let failed = errors.len();
+    for err in errors {
+        error!("{err:?}");
+        report.errors.push(format!("{err:?}"));
+    }
+    report.uploaded = to_upload - failed;
+    summary(&format!("uploaded {}", to_upload - failed));
+    Ok(())
+}
+
+/// Ask the user a yes or no question on the terminal, defaulting to no.
+async fn confirm(question: &str) -> anyhow::Result<bool> {
+    use std::io::Write;
+
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush()?;
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn skip_disabled(files: &mut Vec<MirrorFile>) {
+    files.retain(|file| {
+        if !file.enabled {
+            info!("skipping {}, as it's disabled", file.name);
+        }
+        file.enabled
+    });
+}
+
+/// Manifests in `load_from` changed since the `since` git revision, or `None` if they can't be
+/// determined.
+fn changed_manifests(load_from: &Path, since: &str) -> Option<Vec<PathBuf>> {
+    let output = std::process::Command::new("git")
+        .args(["diff", "--name-only", "--relative", since, "--"])
+        .arg(load_from)
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(
+                "failed to find the manifests changed since {since}, processing all the files:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Err(err) => {
+            warn!("failed to run git, processing all the files: {err}");
+            return None;
+        }
+    };
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            // Deleted manifests don't define any file anymore.
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+            .collect(),
+    )
+}
+
+async fn add_file(args: AddFileArgs) -> anyhow::Result<()> {
+    let toml_file = match args.toml_file {
+        Some(toml_file) => toml_file,
+        None => {
+            let toml_file = auto_toml_file(&args.manifests_dir, &args.path)?;
+            info!("adding the entry to {}", toml_file.display());
+            toml_file
+        }
+    };
+
+    let expected = args.sha256.as_deref().map(str::to_ascii_lowercase);
+    let sha256 = match expected {
+        Some(expected) if !is_sha256_hex(&expected) => {
+            bail!("{expected:?} is not a valid SHA-256 hash (it must be 64 hexadecimal characters)")
+        }
+        Some(expected) if !args.verify => expected,
+        expected => {
+            let (hash, origin) = match &args.from_file {
+                Some(path) => {
+                    warn!(
+                        "trusting the local file {} without checking it matches {}",
+                        path.display(),
+                        args.url
+                    );
+                    // The length of the file itself matters, not the one of its decompressed
+                    // content: an archive of an empty file isn't empty.
+                    let len = tokio::fs::metadata(path)
+                        .await
+                        .with_context(|| format!("failed to read {}", path.display()))?
+                        .len();
+                    if !args.force && len == 0 {
+                        bail!(
+                            "{} is empty (pass --force to add it anyway)",
+                            path.display()
+                        );
+                    }
+                    let hash = get_local_file_hash(path, args.decompress).await?;
+                    (to_hex(&hash), path.display().to_string())
+                }
+                None => {
+                    let hash = Downloader::hashing_only(&args.http)?
+                        .get_file_hash(&args.url, args.decompress, args.force)
+                        .await?;
+                    (to_hex(&hash), args.url.to_string())
+                }
+            };
+            if let Some(expected) = expected
+                && expected != hash
+            {
+                bail!("the hash of {origin} doesn't match (expected {expected}, found {hash})");
+            }
+            hash
+        }
+    };
+
+    let rename_from = if let Some(file_name) = args.url.path().split('/').next_back()
+        && let Some(path_name) = args.path.split('/').next_back()
+        && file_name != path_name
+    {
+        Some(file_name.to_string())
+    } else {
+        None
+    };
+
+    let entry = ManifestFileManaged::new(
+        args.path,
+        sha256,
+        args.url,
+        args.license.unwrap_or_default(),
+        rename_from,
+        args.decompress,
+    );
+    if !args.force
+        && let Some(duplicates) = find_duplicates(&toml_file, &entry)?
+    {
+        bail!(
+            "{} already contains an entry with the same path, URL or hash \
+             (pass --force to add it anyway):\n{duplicates}",
+            toml_file.display()
+        );
+    }
+    append_entry(&toml_file, &entry)
+}
+
+fn append_entry(toml_file: &Path, entry: &ManifestFileManaged) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let entry = toml::to_string(entry)?;
+
+    let file_existed = toml_file.is_file();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(toml_file)?;
+
+    let space = if file_existed { "\n" } else { "" };
+    write!(
+        file,
+        r#"{space}[[files]]
+{entry}"#,
+    )?;
+
+    Ok(())
+}
+
+/// Manifest containing the entries of the tool the file at `path` on the CDN belongs to, named
+/// after the first segment of the path.
+fn auto_toml_file(manifests_dir: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let Some((tool, _)) = path.split_once('/') else {
+        bail!("--auto-toml requires a path starting with a directory, like `<tool>/{path}`");
+    };
+    // The manifest must stay inside the manifests directory.
+    let mut components = Path::new(tool).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) || tool.contains('\\')
+    {
+        bail!("cannot derive a manifest name from `{tool}`");
+    }
+    Ok(manifests_dir.join(format!("{tool}.toml")))
+}
+
+async fn doctor(args: DoctorArgs) -> anyhow::Result<()> {
+    let mut report = Report::default();
+
+    let s3 = S3Storage::new(
+        &args.s3.s3_buckets,
+        args.s3.s3_endpoint_url.as_deref(),
+        args.s3.s3_retries,
+        false,
+        false,
+    )
+    .await?;
+    s3.doctor(&args.known_file, &mut report).await;
+
+    let cdn = CdnReader::new(args.cdn_url, &args.http)?;
+    cdn.doctor(&mut report).await;
+
+    if !report.is_success() {
+        error!("some checks failed, uploading files will likely not work");
+        std::process::exit(1);
+    }
+    info!("all checks passed!");
+    Ok(())
+}
+
+async fn check_upstream(args: CheckUpstreamArgs) -> anyhow::Result<()> {
+    let (mut files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    let mut errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+    skip_disabled(&mut files);
+    files.retain(|file| file.source.url().is_some());
+    if let Some(sample) = args.sample {
+        files.shuffle(&mut rand::thread_rng());
+        files.truncate(sample);
+    }
+
+    info!(
+        "checking the upstream of {} files ({} parallelism)...",
+        files.len(),
+        args.jobs
+    );
+
+    let downloader = Arc::new(Downloader::new(&args.http, None)?);
+    let cdn = if args.compare_members {
+        Some(CdnReader::new(args.cdn_url, &args.http)?)
+    } else {
+        None
+    };
+    let concurrency_limiter = Arc::new(Semaphore::new(args.jobs));
+    let mut taskset = JoinSet::new();
+    for file in files {
+        let downloader = downloader.clone();
+        let concurrency_limiter = concurrency_limiter.clone();
+        let compare_members = args.compare_members;
+        taskset.spawn(async move {
+            let _permit = concurrency_limiter.acquire().await.unwrap();
+            let Some(url) = file.source.url() else {
+                unreachable!();
+            };
+            // Archives are stored to compare their members if their hash changed.
+            let hash = if compare_members && file.format.is_some() {
+                downloader.download_unverified(&file).await
+            } else {
+                downloader
+                    .get_file_hash(url, file.decompress, file.sha256 == EMPTY_SHA256)
+                    .await
+            };
+            (file, hash)
+        });
+    }
+
+    for (file, hash) in taskset.join_all().await {
+        let Some(url) = file.source.url() else {
+            unreachable!();
+        };
+        match hash {
+            Ok(hash) if to_hex(&hash) != file.sha256 => {
+                let mut error = format!(
+                    "upstream of file {} changed (expected {}, downloaded {}): {url}",
+                    file.name,
+                    file.sha256,
+                    to_hex(&hash)
+                );
+                if let Some(cdn) = &cdn {
+                    match changed_members(&downloader, cdn, &file).await {
+                        Ok(Some(changes)) => {
+                            error.push_str("\nchanged files inside the archive:");
+                            for change in changes {
+                                error.push_str(&format!("\n  {change}"));
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            warn!("failed to compare the members of {}: {err:?}", file.name)
+                        }
+                    }
+                }
+                errors.push(error);
+            }
+            Ok(_) => {}
+            Err(err) => errors.push(format!("{err:?}")),
+        }
+    }
+
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    info!("all upstream files match!");
+    Ok(())
+}
+
+/// Compare the files inside the downloaded archive with the hashes recorded when it was uploaded.
+/// Returns `None` if no hashes were recorded for the file.
+async fn changed_members(
+    downloader: &Downloader,
+    cdn: &CdnReader,
+    file: &MirrorFile,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let Some(format) = file.format else {
+        return Ok(None);
+    };
+    let Some(recorded) = cdn.get_text(&format!("{}.members.json", file.name)).await? else {
+        return Ok(None);
+    };
+    let recorded = serde_json::from_str::<BTreeMap<String, String>>(&recorded)
+        .with_context(|| format!("invalid {}.members.json", file.name))?;
+    let path = downloader.path_for(file);
+    let current =
+        tokio::task::spawn_blocking(move || archive::member_hashes(&path, format)).await??;
+    Ok(current.map(|current| archive::diff_members(&recorded, &current)))
+}
+
+fn generate_index(args: GenerateIndexArgs) -> anyhow::Result<()> {
+    let (files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+
+    std::fs::write(&args.output, index::render(&files)?)?;
+    info!(
+        "wrote the index of {} files to {}",
+        files.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+async fn backfill(args: BackfillArgs) -> anyhow::Result<()> {
+    let (mut files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    skip_disabled(&mut files);
+    files.retain(|file| matches!(file.source, Source::Legacy));
+    info!("checking {} legacy files...", files.len());
+
+    let storage = Storage::ReadWrite(Box::new(
+        S3Storage::new(
+            &args.s3.s3_buckets,
+            args.s3.s3_endpoint_url.as_deref(),
+            args.s3.s3_retries,
+            false,
+            false,
+        )
+        .await?,
+    ));
+    let cdn = CdnReader::new(args.cdn_url.clone(), &args.http)?;
+    let downloader = Downloader::hashing_only(&args.http)?;
+
+    let mut errors = Vec::new();
+    let mut backfilled = 0;
+    for file in &files {
+        match backfill_file(&storage, &cdn, &downloader, file, args.dry_run).await {
+            Ok(true) => backfilled += 1,
+            Ok(false) => {}
+            Err(err) => errors.push(format!("{err:?}")),
+        }
+    }
+
+    if !errors.is_empty() {
+        error!("failed to backfill {} file(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    if args.dry_run {
+        info!("{backfilled} file(s) are missing their .sha256 file");
+    } else {
+        info!("backfilled {backfilled} file(s)");
+    }
+    Ok(())
+}
+
+/// Write the `.sha256` file of a legacy entry uploaded without it, returning whether it was
+/// missing. The object is downloaded from the CDN first, and must match the hash in the manifest.
+async fn backfill_file(
+    storage: &Storage,
+    cdn: &CdnReader,
+    downloader: &Downloader,
+    file: &MirrorFile,
+    dry_run: bool,
+) -> anyhow::Result<bool> {
+    match storage.file_status(&file.name).await? {
+        FileStatus::Inconsistent {
+            missing: MissingPart::Sidecar,
+        } => {}
+        FileStatus::Missing => bail!("{} was never uploaded", file.name),
+        _ => return Ok(false),
+    }
+    if dry_run {
+        info!("{} is missing its .sha256 file", file.name);
+        return Ok(true);
+    }
+
+    let url = Url::parse(&cdn.url(&file.name))?;
+    let hash = downloader
+        .get_file_hash(&url, None, file.sha256 == EMPTY_SHA256)
+        .await
+        .with_context(|| format!("failed to download {}", file.name))?;
+    if to_hex(&hash) != file.sha256 {
+        bail!(
+            "the hash of {} doesn't match the manifest (expected {}, uploaded {})",
+            file.name,
+            file.sha256,
+            to_hex(&hash)
+        );
+    }
+    info!("writing {}...", storage::sidecar_key(&file.name));
+    storage
+        .write_contents(
+            &storage::sidecar_key(&file.name),
+            file.sha256.as_bytes(),
+            false,
+        )
+        .await?;
+    Ok(true)
+}
+
+async fn rename(args: RenameArgs) -> anyhow::Result<()> {
+    let (files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    if !files.iter().any(|file| file.name == args.old_name) {
+        bail!("no mirrored file is named {}", args.old_name);
+    }
+    if files.iter().any(|file| file.name == args.new_name) {
+        bail!("a mirrored file is already named {}", args.new_name);
+    }
+
+    let storage = Storage::ReadWrite(Box::new(
+        S3Storage::new(
+            &args.s3.s3_buckets,
+            args.s3.s3_endpoint_url.as_deref(),
+            args.s3.s3_retries,
+            false,
+            false,
+        )
+        .await?,
+    ));
+    match storage.file_status(&args.old_name).await? {
+        FileStatus::Present { .. } => {}
+        FileStatus::Missing => bail!("{} was not uploaded yet", args.old_name),
+        FileStatus::Inconsistent { .. } => bail!(
+            "{} is only partially uploaded, run `upload` to fix it first",
+            args.old_name
+        ),
+        FileStatus::CorruptSidecar { .. } => {
+            bail!("{} has a corrupt .sha256 file", args.old_name)
+        }
+    }
+
+    // The object and its other sidecars are copied before its `.sha256` file, so that an
+    // interrupted rename is detected as a missing file by the next upload rather than as a
+    // present one.
+    let mut suffixes = vec![""];
+    for &suffix in storage::OPTIONAL_SIDECARS {
+        if storage
+            .object_info(&format!("{}{suffix}", args.old_name))
+            .await?
+            .is_some()
+        {
+            suffixes.push(suffix);
+        }
+    }
+    suffixes.push(".sha256");
+    for suffix in &suffixes {
+        info!(
+            "copying {}{suffix} to {}{suffix}...",
+            args.old_name, args.new_name
+        );
+        storage
+            .copy_file(
+                &format!("{}{suffix}", args.old_name),
+                &format!("{}{suffix}", args.new_name),
+            )
+            .await?;
+    }
+
+    let manifest = rename_entry(&args.manifests_dir, &args.old_name, &args.new_name)?;
+    info!(
+        "renamed {} to {} in {}",
+        args.old_name,
+        args.new_name,
+        manifest.display()
+    );
+
+    if args.delete_old {
+        // The `.sha256` file is deleted first, for the same reason it's copied last.
+        for suffix in suffixes.iter().rev() {
+            info!("deleting {}{suffix}...", args.old_name);
+            storage
+                .delete_file(&format!("{}{suffix}", args.old_name))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn expand_index(mut args: ExpandIndexArgs) -> anyhow::Result<()> {
+    // Relative links are only resolved inside the directory if its URL ends with a slash.
+    if !args.url.path().ends_with('/') {
+        args.url.set_path(&format!("{}/", args.url.path()));
+    }
+
+    let downloader = Downloader::hashing_only(&args.http)?;
+    let listing = downloader.get_text(&args.url).await?;
+    let urls = args.format.parse(&args.url, &listing)?;
+    info!("found {} files in {}", urls.len(), args.url);
+
+    let prefix = args.path_prefix.trim_end_matches('/');
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+    for url in urls {
+        let hash = match downloader.get_file_hash(&url, None, false).await {
+            Ok(hash) => hash,
+            Err(err) => {
+                errors.push(format!("{err:?}"));
+                continue;
+            }
+        };
+        let file_name = url.path().split('/').next_back().unwrap_or_default();
+        let entry = ManifestFileManaged::new(
+            format!("{prefix}/{file_name}"),
+            to_hex(&hash),
+            url,
+            args.license.clone().unwrap_or_default(),
+            None,
+            None,
+        );
+        entries.push(format!("[[files]]\n{}", toml::to_string(&entry)?));
+    }
+
+    // The entries are printed rather than added to a manifest, so that they can be reviewed.
+    println!("{}", entries.join("\n"));
+
+    if !errors.is_empty() {
+        error!("failed to download {} file(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn import_sums(mut args: ImportSumsArgs) -> anyhow::Result<()> {
+    // Relative file names are only resolved inside the directory if its URL ends with a slash.
+    if !args.base_url.path().ends_with('/') {
+        args.base_url
+            .set_path(&format!("{}/", args.base_url.path()));
+    }
+
+    let downloader = Downloader::hashing_only(&args.http)?;
+    let sums = downloader.get_text(&args.sums_url).await?;
+    let mut checksums = listing::parse_checksums(&args.base_url, &sums)?;
+    if let Some(include) = &args.include {
+        checksums.retain(|checksum| include.matches(&checksum.name));
+    }
+    info!("found {} files in {}", checksums.len(), args.sums_url);
+
+    let prefix = args.path_prefix.trim_end_matches('/');
+    let (mut added, mut skipped) = (0, 0);
+    for checksum in checksums {
+        let entry = ManifestFileManaged::new(
+            format!("{prefix}/{}", checksum.name),
+            checksum.sha256,
+            checksum.url,
+            args.license.clone().unwrap_or_default(),
+            None,
+            None,
+        );
+        if !args.force
+            && let Some(duplicates) = find_duplicates(&args.toml_file, &entry)?
+        {
+            warn!(
+                "skipping {}, {} already contains an entry with the same path, URL or hash:\n{duplicates}",
+                checksum.name,
+                args.toml_file.display()
+            );
+            skipped += 1;
+            continue;
+        }
+        append_entry(&args.toml_file, &entry)?;
+        added += 1;
+    }
+    info!(
+        "added {added} entries to {}, skipped {skipped}",
+        args.toml_file.display()
+    );
+    Ok(())
+}
+
+fn format_check(args: FormatCheckArgs) -> anyhow::Result<()> {
+    let unformatted = check_format(&args.manifests_dir, args.write)?;
+    if args.write {
+        for path in &unformatted {
+            info!("formatted {}", path.display());
+        }
+    } else if !unformatted.is_empty() {
+        error!(
+            "found {} manifest(s) not formatted canonically (run `format-check --write` to fix)",
+            unformatted.len()
+        );
+        for path in unformatted {
+            error!("{}", path.display());
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn validate(args: ValidateArgs) -> anyhow::Result<()> {
+    let options = args.load.options(false);
+    let (files, errors) = if args.manifests_dir == Path::new("-") {
+        let source = std::io::read_to_string(std::io::stdin()).context("failed to read stdin")?;
+        load_manifest_source(Path::new("<stdin>"), source, &options)?
+    } else {
+        load_manifests(&args.manifests_dir, &options)?
+    };
+    if args.json_errors {
+        println!("{}", serde_json::to_string_pretty(&errors)?);
+    }
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        if !args.json_errors {
+            for error in errors {
+                error!("{error}");
+            }
+        }
+        std::process::exit(1);
+    }
+    info!("validated {} files", files.len());
+    Ok(())
+}
+
+fn diff(args: DiffArgs) -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+    let (before, mut before_errors) = load_manifests(&args.before, &LoadOptions::default())?;
+    let (after, mut after_errors) = load_manifests(&args.after, &LoadOptions::default())?;
+    errors.append(&mut before_errors);
+    errors.append(&mut after_errors);
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+
+    print!("{}", diff::render(&before, &after, args.output)?);
+    Ok(())
+}
+
+async fn health_check(args: HealthCheckArgs) -> anyhow::Result<()> {
+    let (mut files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    skip_disabled(&mut files);
+    files.retain(|file| file.source.url().is_some());
+    info!(
+        "checking the health of {} files ({} parallelism)...",
+        files.len(),
+        args.jobs
+    );
+
+    let downloader = Arc::new(Downloader::hashing_only(&args.http)?);
+    let cdn = Arc::new(CdnReader::new(args.cdn_url, &args.http)?);
+    let concurrency_limiter = Arc::new(Semaphore::new(args.jobs));
+    let mut taskset = JoinSet::new();
+    for file in files {
+        let downloader = downloader.clone();
+        let cdn = cdn.clone();
+        let concurrency_limiter = concurrency_limiter.clone();
+        taskset.spawn(async move {
+            let _permit = concurrency_limiter.acquire().await.unwrap();
+            let Some(url) = file.source.url() else {
+                unreachable!();
+            };
+            Health {
+                upstream: Probe::from(downloader.head_status(url).await),
+                cdn: Probe::from(cdn.head_status(&file.name).await.map(Some)),
+                name: file.name,
+            }
+        });
+    }
+    let mut entries = taskset.join_all().await;
+
+    print!("{}", health::render(&mut entries, args.output)?);
+    let dead = entries
+        .iter()
+        .filter(|entry| entry.upstream.is_broken())
+        .count();
+    if dead > 0 {
+        error!("{dead} upstream(s) are not reachable");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn audit(args: AuditArgs) -> anyhow::Result<()> {
+    let (mut files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+    skip_disabled(&mut files);
+    info!(
+        "auditing {} files ({} parallelism)...",
+        files.len(),
+        args.jobs
+    );
+
+    let storage = Arc::new(Storage::ReadOnly(CdnReader::new(args.cdn_url, &args.http)?));
+    let concurrency_limiter = Arc::new(Semaphore::new(args.jobs));
+    let mut taskset = JoinSet::new();
+    for file in files {
+        let storage = storage.clone();
+        let concurrency_limiter = concurrency_limiter.clone();
+        taskset.spawn(async move {
+            let _permit = concurrency_limiter.acquire().await.unwrap();
+            let status = storage
+                .file_status_with_retries(&file.name, DEFAULT_STATUS_RETRIES)
+                .await;
+            (file, status)
+        });
+    }
+
+    let mut audit = Audit::default();
+    let mut results = taskset.join_all().await;
+    results.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+    for (file, status) in &results {
+        audit.record(status, matches!(file.source, Source::Legacy));
+        if args.summary_only {
+            continue;
+        }
+        let name = &file.name;
+        match status {
+            Ok(FileStatus::Present { .. }) => {}
+            Ok(FileStatus::Missing) => warn!("{name} is missing"),
+            Ok(FileStatus::Inconsistent {
+                missing: MissingPart::Sidecar,
+            }) => warn!("{name} has no .sha256 file"),
+            Ok(FileStatus::Inconsistent {
+                missing: MissingPart::Object { .. },
+            }) => warn!("{name} is missing, but not {name}.sha256"),
+            Ok(FileStatus::CorruptSidecar { .. }) => warn!("{name} has a corrupt .sha256 file"),
+            Err(err) => warn!("failed to check {name}: {err:#}"),
+        }
+    }
+
+    print!("{}", audit.render(args.output)?);
+    if audit.failed() {
+        error!(
+            "{} file(s) are missing from the CDN and {} couldn't be checked",
+            audit.missing + audit.missing_objects,
+            audit.errors
+        );
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn keys(args: KeysArgs) -> anyhow::Result<()> {
+    let (files, errors) = load_manifests(&args.manifests_dir, &LoadOptions::default())?;
+    if !errors.is_empty() {
+        error!("found {} error(s)", errors.len());
+        for error in errors {
+            error!("{error}");
+        }
+        std::process::exit(1);
+    }
+
+    let prefix = storage::normalize_prefix(args.prefix);
+    for file in files {
+        let key = storage::object_key(prefix.as_deref(), &file.name);
+        println!("{key}\t{}", storage::sidecar_key(&key));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ValidationErrorKind;
+    use crate::test_utils::{HELLO_SHA256, MockServer};
+
+    /// Manifest written by [`add_file_in_child`], set when it's run by
+    /// [`add_file_without_temp_dir`].
+    const CHILD_TOML: &str = "CI_MIRRORS_TEST_TOML";
+
+    #[test]
+    fn add_file_without_temp_dir() {
+        // The temporary directory is the same for the whole process, so the command is run by
+        // this test binary in a child process. The directory is missing rather than read-only,
+        // as root can write to read-only directories.
+        let dir = tempfile::tempdir().unwrap();
+        let toml = dir.path().join("a.toml");
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "cli::tests::add_file_in_child", "--ignored"])
+            .env("TMPDIR", dir.path().join("missing"))
+            .env(CHILD_TOML, &toml)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+        let content = std::fs::read_to_string(&toml).unwrap();
+        assert!(content.contains(HELLO_SHA256), "{content}");
+    }
+
+    #[tokio::test]
+    #[ignore = "run by add_file_without_temp_dir"]
+    async fn add_file_in_child() {
+        let toml = std::env::var(CHILD_TOML).unwrap();
+        let server = MockServer::start(200, b"hello");
+        let url = server.url("a.tar.gz");
+        let cli = Cli::try_parse_from([
+            "ci-mirrors",
+            "add-file",
+            &url,
+            "--path",
+            "a.tar.gz",
+            "--toml-file",
+            &toml,
+            "--license",
+            "MIT",
+        ])
+        .unwrap();
+        let Command::AddFile(args) = cli.command else {
+            unreachable!();
+        };
+        add_file(args).await.unwrap();
+    }
+
+    #[test]
+    fn disabled_entries_are_deduplicated_then_skipped() {
+        let source = r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+
+[[files]]
+name = "a.tar.gz"
+source = "https://example.com/v2/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9825"
+license = "MIT"
+enabled = false
+"#;
+        let (mut files, errors) = manifest::load_manifest_source(
+            Path::new("a.toml"),
+            source.into(),
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        // A disabled entry still claims its path, so that enabling it later can't conflict.
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, ValidationErrorKind::DuplicatePath));
+
+        skip_disabled(&mut files);
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].source.url().unwrap().as_str(),
+            "https://example.com/a.tar.gz"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context as _, Error};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Configuration file loaded from the current directory when `--config` is not passed.
+const DEFAULT_PATH: &str = "mirror.toml";
+
+/// Defaults for the `upload` command, overridden by the flags passed on the command line. Each
+/// key matches the flag with the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    pub(crate) cdn_url: Option<String>,
+    pub(crate) s3_buckets: Option<Vec<String>>,
+    pub(crate) s3_endpoint_url: Option<String>,
+    pub(crate) s3_retries: Option<u32>,
+    pub(crate) status_retries: Option<u32>,
+    pub(crate) jobs: Option<usize>,
+    pub(crate) prefix: Option<String>,
+    pub(crate) status_cache: Option<PathBuf>,
+    pub(crate) dedup_index: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) write_sri: bool,
+    #[serde(default)]
+    pub(crate) tag_objects: bool,
+    #[serde(default)]
+    pub(crate) verify_after_upload: bool,
+    #[serde(default)]
+    pub(crate) etag_status: bool,
+    #[serde(default)]
+    pub(crate) require_license: bool,
+    #[serde(default)]
+    pub(crate) deny_insecure_urls: bool,
+    #[serde(default)]
+    pub(crate) case_insensitive_paths: bool,
+    pub(crate) denied_extensions: Option<Vec<String>>,
+    pub(crate) allowed_extensions: Option<Vec<String>>,
+    #[serde(default)]
+    pub(crate) no_space_check: bool,
+    #[serde(default)]
+    pub(crate) compress_temp: bool,
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) max_download_bytes: Option<u64>,
+    pub(crate) http_timeout: Option<u64>,
+    pub(crate) http_connect_timeout: Option<u64>,
+    pub(crate) min_download_speed: Option<usize>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) per_host_jobs: Option<usize>,
+    pub(crate) allow_s3_source_buckets: Option<Vec<String>>,
+    pub(crate) max_bandwidth: Option<u64>,
+}
+
+impl Config {
+    /// Load the configuration at `path`, or at `mirror.toml` if it exists when no path is given.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_PATH).is_file() => Path::new(DEFAULT_PATH),
+            None => return Ok(Self::default()),
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
@@ -0,0 +1,136 @@
+use crate::manifest::MirrorFile;
+use crate::utils::OutputFormat;
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+struct Content<'a> {
+    sha256: &'a str,
+    source: Option<&'a str>,
+}
+
+impl<'a> Content<'a> {
+    fn of(file: &'a MirrorFile) -> Self {
+        Self {
+            sha256: &file.sha256,
+            source: file.source.url().map(|url| url.as_str()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Change<'a> {
+    before: Content<'a>,
+    after: Content<'a>,
+}
+
+/// Entries are keyed by name, so that the output is sorted.
+#[derive(Default, Serialize)]
+struct Diff<'a> {
+    added: BTreeMap<&'a str, Content<'a>>,
+    removed: BTreeMap<&'a str, Content<'a>>,
+    changed: BTreeMap<&'a str, Change<'a>>,
+}
+
+/// Render the entries added, removed or changed between the `before` and `after` files. Entries
+/// are matched by name, and are changed when either their hash or their source changed. The text
+/// output is a Markdown list, meant to be pasted in a PR description.
+pub(crate) fn render(
+    before: &[MirrorFile],
+    after: &[MirrorFile],
+    format: OutputFormat,
+) -> Result<String, Error> {
+    let before = before
+        .iter()
+        .map(|file| (file.name.as_str(), Content::of(file)))
+        .collect::<BTreeMap<_, _>>();
+    let after_names = after
+        .iter()
+        .map(|file| file.name.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut diff = Diff::default();
+    for file in after {
+        let content = Content::of(file);
+        match before.get(file.name.as_str()) {
+            None => {
+                diff.added.insert(&file.name, content);
+            }
+            Some(&old) if old != content => {
+                diff.changed.insert(
+                    &file.name,
+                    Change {
+                        before: old,
+                        after: content,
+                    },
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, content) in before {
+        if !after_names.contains(name) {
+            diff.removed.insert(name, content);
+        }
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let mut output = serde_json::to_string_pretty(&diff)?;
+            output.push('\n');
+            Ok(output)
+        }
+        OutputFormat::Text => Ok(render_text(&diff)),
+    }
+}
+
+fn render_text(diff: &Diff<'_>) -> String {
+    let mut output = String::new();
+    if diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty() {
+        output.push_str("No differences.\n");
+        return output;
+    }
+    for (title, entries) in [("Added", &diff.added), ("Removed", &diff.removed)] {
+        if entries.is_empty() {
+            continue;
+        }
+        writeln!(output, "### {title} ({})\n", entries.len()).unwrap();
+        for (name, content) in entries {
+            writeln!(
+                output,
+                "- `{name}` (`{}`, {})",
+                content.sha256,
+                content.source.unwrap_or("legacy")
+            )
+            .unwrap();
+        }
+        output.push('\n');
+    }
+    if !diff.changed.is_empty() {
+        writeln!(output, "### Changed ({})\n", diff.changed.len()).unwrap();
+        for (name, change) in &diff.changed {
+            writeln!(output, "- `{name}`").unwrap();
+            if change.before.sha256 != change.after.sha256 {
+                writeln!(
+                    output,
+                    "  - sha256: `{}` -> `{}`",
+                    change.before.sha256, change.after.sha256
+                )
+                .unwrap();
+            }
+            if change.before.source != change.after.source {
+                writeln!(
+                    output,
+                    "  - source: {} -> {}",
+                    change.before.source.unwrap_or("legacy"),
+                    change.after.source.unwrap_or("legacy")
+                )
+                .unwrap();
+            }
+        }
+        output.push('\n');
+    }
+    output
+}
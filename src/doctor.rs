@@ -0,0 +1,26 @@
+use anyhow::Error;
+use tracing::{error, info, warn};
+
+/// Collects the outcome of the checks performed by the `doctor` command.
+#[derive(Default)]
+pub(crate) struct Report {
+    critical_failures: usize,
+}
+
+impl Report {
+    /// Print the outcome of a check. Failures of critical checks make the command fail.
+    pub(crate) fn check(&mut self, name: &str, critical: bool, result: Result<(), Error>) {
+        match result {
+            Ok(()) => info!("pass: {name}"),
+            Err(err) if critical => {
+                error!("fail: {name}\n  {err:?}");
+                self.critical_failures += 1;
+            }
+            Err(err) => warn!("{name}\n  {err:?}"),
+        }
+    }
+
+    pub(crate) fn is_success(&self) -> bool {
+        self.critical_failures == 0
+    }
+}
@@ -1,57 +1,215 @@
-use crate::manifest::{MirrorFile, Source};
-use crate::utils::to_hex;
-use anyhow::{Error, bail};
+use crate::http::{self, HttpArgs};
+use crate::manifest::{Compression, MirrorFile};
+use crate::utils::{self, PROGRESS_TARGET, to_hex};
+use crate::{archive, ftp, signature};
+use anyhow::{Context as _, Error, anyhow, bail};
+use aws_config::SdkConfig;
 use futures::TryStreamExt as _;
-use reqwest::{Client, Url};
-use sha2::{Digest as _, Sha256};
-use std::path::PathBuf;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use percent_encoding::percent_decode_str;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Client, Response, StatusCode, Url};
+use sha2::{Digest as _, Sha256, Sha512};
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use tempfile::TempDir;
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempDir, TempPath};
 use tokio::fs::File;
 use tokio::io::{AsyncWrite, BufWriter};
-use tokio_util::io::StreamReader;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{info, instrument, warn};
+
+const PROGRESS_TEMPLATE: &str =
+    "{wide_msg} {bytes}/{total_bytes} ({bytes_per_sec}) [{bar:30}] {eta}";
+
+/// SHA-256 of an empty file.
+pub(crate) const EMPTY_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
 
 pub(crate) struct Downloader {
-    storage: TempDir,
+    /// Directory the files are downloaded to, missing for the downloaders only hashing them.
+    storage: Option<TempDir>,
     http: Client,
+    ftp_connect_timeout: Duration,
+    allow_file_urls: bool,
+    min_throughput: Option<usize>,
+    per_host_jobs: usize,
+    /// Limits the concurrent downloads from each upstream host, created on first use.
+    host_limiters: Mutex<HashMap<String, Arc<Semaphore>>>,
+    /// Progress bars of the HTTP downloads, only shown when stderr is a terminal and the command
+    /// is not quiet.
+    progress: Option<MultiProgress>,
+    throttle: Option<Throttle>,
+    allowed_s3_buckets: Vec<String>,
+    /// Endpoint the `s3://` URLs are downloaded from instead of AWS, set with
+    /// [`Downloader::s3_endpoint_url`].
+    s3_endpoint_url: Option<String>,
+    /// Compress the downloaded files once verified, see [`Downloader::compress_storage`].
+    compress_storage: bool,
+    /// Client used to download `s3://` URLs, created on first use from the environment.
+    s3: OnceCell<aws_sdk_s3::Client>,
 }
 
 impl Downloader {
-    pub(crate) fn new() -> Result<Self, Error> {
+    /// Downloader storing the files in a temporary directory, created in `cache_dir` if given or
+    /// in the system temporary directory otherwise.
+    pub(crate) fn new(http: &HttpArgs, cache_dir: Option<&Path>) -> Result<Self, Error> {
+        let storage = match cache_dir {
+            Some(dir) => TempDir::new_in(dir)
+                .with_context(|| format!("failed to create a directory in {}", dir.display()))?,
+            None => TempDir::new()?,
+        };
+        Self::with_storage(http, Some(storage))
+    }
+
+    /// Downloader that doesn't store the files on disk, for the commands only hashing them or
+    /// reading listings. Downloading a file to disk with it panics.
+    pub(crate) fn hashing_only(http: &HttpArgs) -> Result<Self, Error> {
+        Self::with_storage(http, None)
+    }
+
+    fn with_storage(http: &HttpArgs, storage: Option<TempDir>) -> Result<Self, Error> {
         Ok(Self {
-            storage: TempDir::new()?,
-            http: Client::builder()
-                .user_agent("https://github.com/rust-lang/ci-mirrors")
-                .build()?,
+            storage,
+            http: http.client()?,
+            ftp_connect_timeout: http.connect_timeout(),
+            allow_file_urls: http.allow_file_urls,
+            min_throughput: http.min_throughput(),
+            per_host_jobs: http.per_host_jobs,
+            host_limiters: Mutex::new(HashMap::new()),
+            progress: utils::progress_bars(),
+            throttle: http.max_bandwidth.map(Throttle::new),
+            allowed_s3_buckets: http.allow_s3_source_buckets.clone(),
+            s3_endpoint_url: None,
+            compress_storage: false,
+            s3: OnceCell::new(),
         })
     }
 
-    pub(crate) async fn download(&self, file: &MirrorFile) -> Result<(), Error> {
-        let url = match &file.source {
-            Source::Url(url) => url,
-            Source::Legacy => bail!("cannot download legacy file {}", file.name),
+    /// Store the files compressed with zstd once they are downloaded and verified, to reduce the
+    /// disk space used when many large files are downloaded before being uploaded. They must be
+    /// decompressed with [`decompress_stored`] before being read.
+    pub(crate) fn compress_storage(mut self, compress: bool) -> Self {
+        self.compress_storage = compress;
+        self
+    }
+
+    /// Download the `s3://` URLs from this endpoint instead of AWS, like the uploads with
+    /// `--s3-endpoint-url`.
+    pub(crate) fn s3_endpoint_url(mut self, endpoint_url: Option<String>) -> Self {
+        self.s3_endpoint_url = endpoint_url;
+        self
+    }
+
+    /// Wait until a download from the host of `url` can start. The returned permit must be kept
+    /// until the download completes.
+    async fn acquire_host(&self, url: &Url) -> OwnedSemaphorePermit {
+        let limiter = self
+            .host_limiters
+            .lock()
+            .unwrap()
+            .entry(url.host_str().unwrap_or_default().to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host_jobs)))
+            .clone();
+        limiter.acquire_owned().await.unwrap()
+    }
+
+    /// Download the file and verify its hash. Returns the hashes of the downloaded file.
+    #[instrument(skip_all, fields(file = %file.name))]
+    pub(crate) async fn download(&self, file: &MirrorFile) -> Result<Hashes, Error> {
+        let Some(url) = file.source.url() else {
+            bail!("cannot download legacy file {}", file.name);
         };
-        eprintln!("downloading {url}...");
+        info!(target: PROGRESS_TARGET, "downloading {url}...");
 
-        let resp = self.http.get(url.clone()).send().await?;
-        if !resp.status().is_success() {
-            bail!(
-                "failed to download with status {}: {url}\n=== body ===\n{}\n============\n",
-                resp.status(),
-                resp.text().await?
-            );
+        let dest = File::create(self.path_for(file)).await?;
+        let writer = self
+            .fetch(
+                url,
+                HashWriter::new(BufWriter::new(dest), file.decompress, file.sha512.is_some())?,
+            )
+            .await?;
+
+        let hashes = self.verify(file, url, writer).await?;
+        if self.compress_storage {
+            self.compress(file).await?;
         }
+        Ok(hashes)
+    }
 
-        let mut reader = StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other));
+    /// Compress the stored file to its [`Downloader::compressed_path_for`], removing the original.
+    async fn compress(&self, file: &MirrorFile) -> Result<(), Error> {
+        let path = self.path_for(file);
+        let Some(compressed) = self.compressed_path_for(file) else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || {
+            let input = std::fs::File::open(&path)?;
+            let output = std::fs::File::create(&compressed)?;
+            zstd::stream::copy_encode(input, std::io::BufWriter::new(output), 0)?;
+            std::fs::remove_file(&path)?;
+            Ok::<_, Error>(())
+        })
+        .await?
+        .with_context(|| format!("failed to compress the download of {}", file.name))
+    }
 
-        let dest = File::create(self.path_for(file)).await?;
-        let mut writer = Sha256Writer::new(BufWriter::new(dest));
-        tokio::io::copy(&mut reader, &mut writer).await?;
+    /// Download the file and verify its hash without storing it, for files that won't be
+    /// uploaded. Files with a `format` or a `signature` are still stored, as validating them
+    /// requires reading them back.
+    #[instrument(skip_all, fields(file = %file.name))]
+    pub(crate) async fn check(&self, file: &MirrorFile) -> Result<Hashes, Error> {
+        if file.format.is_some() || file.signature.is_some() {
+            return self.download(file).await;
+        }
+        let Some(url) = file.source.url() else {
+            bail!("cannot download legacy file {}", file.name);
+        };
+        info!(target: PROGRESS_TARGET, "downloading {url}...");
+
+        let writer = self
+            .fetch(
+                url,
+                HashWriter::new(tokio::io::sink(), file.decompress, file.sha512.is_some())?,
+            )
+            .await?;
+        self.verify(file, url, writer).await
+    }
+
+    /// Check that the downloaded file matches the manifest, returning its hashes.
+    async fn verify<W: AsyncWrite>(
+        &self,
+        file: &MirrorFile,
+        url: &Url,
+        writer: HashWriter<W>,
+    ) -> Result<Hashes, Error> {
+        // Some servers respond successfully with an empty body, which would otherwise be
+        // reported as a confusing hash mismatch.
+        if writer.len == 0 && file.sha256 != EMPTY_SHA256 {
+            bail!("downloaded 0 bytes from {url}");
+        }
 
-        eprintln!("  -> success! the size is {}", format_size(writer.len));
+        // The file must be fully written before its signature is verified, and the signature is
+        // verified before the hash so that a tampered file is reported as such.
+        let hashes = writer.finish()?;
+        if let Some(signature) = &file.signature {
+            let content = self
+                .get_bytes(&signature.url)
+                .await
+                .context("failed to download the signature")?;
+            let path = self.path_for(file);
+            let key = signature.key.clone();
+            tokio::task::spawn_blocking(move || signature::verify(&path, &content, &key))
+                .await?
+                .with_context(|| format!("failed to verify the signature of {url}"))?;
+        }
 
-        let sha256 = to_hex(&writer.sha256);
+        let sha256 = to_hex(&hashes.content);
         if sha256 != file.sha256 {
             bail!(
                 "the hash of {} doesn't match (expected {}, downloaded {})",
@@ -60,35 +218,415 @@ impl Downloader {
                 sha256
             );
         }
+        if let Some(expected) = &file.sha512 {
+            let sha512 = hashes.sha512.as_ref().map(to_hex).unwrap_or_default();
+            if sha512 != *expected {
+                bail!(
+                    "the SHA-512 of {url} doesn't match (expected {expected}, downloaded {sha512})"
+                );
+            }
+        }
+
+        if let Some(format) = file.format {
+            let path = self.path_for(file);
+            tokio::task::spawn_blocking(move || archive::validate(&path, format))
+                .await?
+                .with_context(|| format!("failed to validate {url}"))?;
+        }
 
-        Ok(())
+        Ok(hashes)
     }
 
-    pub(crate) async fn get_file_hash(&self, url: &Url) -> Result<Sha256, Error> {
-        let mut writer = Sha256Writer::new(tokio::io::sink());
-        eprintln!("downloading {url}...");
+    /// Download the file at `url` and return its hash. When `decompress` is set, the hash is
+    /// computed on the decompressed content. Empty downloads are an error unless `allow_empty`
+    /// is set.
+    #[instrument(skip_all, fields(%url))]
+    pub(crate) async fn get_file_hash(
+        &self,
+        url: &Url,
+        decompress: Option<Compression>,
+        allow_empty: bool,
+    ) -> Result<Sha256, Error> {
+        info!(target: PROGRESS_TARGET, "downloading {url}...");
+        let writer = self
+            .fetch(url, HashWriter::new(tokio::io::sink(), decompress, false)?)
+            .await?;
+        if writer.len == 0 && !allow_empty {
+            bail!("downloaded 0 bytes from {url}");
+        }
+        Ok(writer.finish()?.content)
+    }
 
-        let mut reader = StreamReader::new(
-            self.http
-                .get(url.clone())
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes_stream()
-                .map_err(std::io::Error::other),
+    /// Download the file to disk without verifying it, returning the hash of its content. This is
+    /// meant to inspect files whose hash is known not to match.
+    #[instrument(skip_all, fields(file = %file.name))]
+    pub(crate) async fn download_unverified(&self, file: &MirrorFile) -> Result<Sha256, Error> {
+        let Some(url) = file.source.url() else {
+            bail!("cannot download legacy file {}", file.name);
+        };
+        info!(target: PROGRESS_TARGET, "downloading {url}...");
+        let dest = File::create(self.path_for(file)).await?;
+        let writer = self
+            .fetch(
+                url,
+                HashWriter::new(BufWriter::new(dest), file.decompress, file.sha512.is_some())?,
+            )
+            .await?;
+        Ok(writer.finish()?.content)
+    }
+
+    /// Status of a HEAD request to `url`, without downloading it. Returns `None` for URLs other
+    /// than HTTP ones, which can't be checked this way.
+    pub(crate) async fn head_status(&self, url: &Url) -> Result<Option<StatusCode>, Error> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Ok(None);
+        }
+        let _permit = self.acquire_host(url).await;
+        let resp = self
+            .http
+            .head(url.clone())
+            .send()
+            .await
+            .map_err(|e| http::map_err(e, url.as_str()))?;
+        Ok(Some(resp.status()))
+    }
+
+    /// Size of the file at `url`, if known. Only HTTP URLs are supported.
+    pub(crate) async fn content_length(&self, url: &Url) -> Result<Option<u64>, Error> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Ok(None);
+        }
+        let _permit = self.acquire_host(url).await;
+        let resp = self
+            .http
+            .head(url.clone())
+            .send()
+            .await
+            .map_err(|e| http::map_err(e, url.as_str()))?;
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+        Ok(resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()))
+    }
+
+    /// Space available on the filesystem the files are downloaded to, in bytes.
+    pub(crate) fn available_space(&self) -> Result<u64, Error> {
+        Ok(fs2::available_space(self.storage_path())?)
+    }
+
+    /// Download the page at `url`, such as a directory listing, as text.
+    pub(crate) async fn get_text(&self, url: &Url) -> Result<String, Error> {
+        self.get(url)
+            .await?
+            .text()
+            .await
+            .map_err(|e| http::map_err(e, url.as_str()))
+    }
+
+    async fn get_bytes(&self, url: &Url) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .get(url)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| http::map_err(e, url.as_str()))?
+            .to_vec())
+    }
+
+    async fn get(&self, url: &Url) -> Result<Response, Error> {
+        let resp = self
+            .http
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| http::map_err(e, url.as_str()))?;
+        let status = resp.status();
+        if !status.is_success() {
+            bail!(
+                "failed to download with status {status}: {url}\n=== body ===\n{}\n============\n",
+                http::body_snippet(resp).await
+            );
+        }
+        Ok(resp)
+    }
+
+    /// Download the contents of `url` into `writer`, picking the protocol based on the scheme.
+    async fn fetch<W: AsyncWrite + Send + 'static>(
+        &self,
+        url: &Url,
+        writer: HashWriter<W>,
+    ) -> Result<HashWriter<W>, Error> {
+        let _permit = self.acquire_host(url).await;
+        let start = Instant::now();
+        let writer = self.fetch_inner(url, writer).await?;
+        self.report_throughput(url, &writer, start);
+        Ok(writer)
+    }
+
+    fn report_throughput<W: AsyncWrite>(&self, url: &Url, writer: &HashWriter<W>, start: Instant) {
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let throughput = (writer.len as f64 / elapsed) as usize;
+        info!(
+            target: PROGRESS_TARGET,
+            "success! {} in {elapsed:.1}s ({}/s)",
+            format_size(writer.len),
+            format_size(throughput)
         );
-        tokio::io::copy(&mut reader, &mut writer).await?;
+        if let Some(min_throughput) = self.min_throughput
+            && throughput < min_throughput
+        {
+            warn!(
+                "downloading {url} was slower than {}/s, the source might be unreliable",
+                format_size(min_throughput)
+            );
+        }
+    }
+
+    async fn fetch_inner<W: AsyncWrite + Send + 'static>(
+        &self,
+        url: &Url,
+        writer: HashWriter<W>,
+    ) -> Result<HashWriter<W>, Error> {
+        match url.scheme() {
+            "http" | "https" => {
+                let resp = self
+                    .http
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .map_err(|e| http::map_err(e, url.as_str()))?;
+                self.write_response(url, resp, writer).await
+            }
+            "ftp" | "ftps" => {
+                // The FTP client is blocking, so bridge the async writer to run it on a
+                // blocking task.
+                let url = url.clone();
+                let connect_timeout = self.ftp_connect_timeout;
+                let mut bridge = SyncIoBridge::new(writer);
+                Ok(tokio::task::spawn_blocking(move || -> Result<_, Error> {
+                    ftp::retrieve(&url, connect_timeout, &mut bridge)?;
+                    bridge.flush()?;
+                    Ok(bridge.into_inner())
+                })
+                .await??)
+            }
+            "s3" => {
+                let bucket = url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("missing bucket in S3 URL: {url}"))?;
+                if !self
+                    .allowed_s3_buckets
+                    .iter()
+                    .any(|allowed| allowed == bucket)
+                {
+                    bail!(
+                        "downloading from the S3 bucket {bucket} is not allowed, pass \
+                         --allow-s3-source-bucket {bucket} to allow it: {url}"
+                    );
+                }
+                let key = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8()?;
+                let client = self
+                    .s3
+                    .get_or_init(|| async { self.s3_client(&aws_config::load_from_env().await) })
+                    .await;
+                let object = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "failed to download {url}, the AWS credentials in the environment \
+                             must be allowed to call s3:GetObject on it"
+                        )
+                    })?;
+                let mut body = object.body.into_async_read();
+                let mut writer = writer;
+                tokio::io::copy(&mut body, &mut writer)
+                    .await
+                    .with_context(|| format!("failed to download {url}"))?;
+                Ok(writer)
+            }
+            "file" if self.allow_file_urls => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|()| anyhow!("invalid file URL: {url}"))?;
+                let mut file = File::open(&path)
+                    .await
+                    .with_context(|| format!("failed to open {}", path.display()))?;
+                let mut writer = writer;
+                tokio::io::copy(&mut file, &mut writer)
+                    .await
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Ok(writer)
+            }
+            "file" => bail!("file URLs are only allowed with --allow-file-urls: {url}"),
+            scheme => bail!("unsupported URL scheme `{scheme}`: {url}"),
+        }
+    }
 
-        eprintln!("  -> success! the size is {}", format_size(writer.len));
-        Ok(writer.sha256)
+    /// Client downloading the `s3://` URLs with the credentials of `config`.
+    fn s3_client(&self, config: &SdkConfig) -> aws_sdk_s3::Client {
+        let mut builder = aws_sdk_s3::config::Builder::from(config);
+        if let Some(endpoint_url) = &self.s3_endpoint_url {
+            builder = builder.endpoint_url(endpoint_url).force_path_style(true);
+        }
+        aws_sdk_s3::Client::from_conf(builder.build())
     }
 
+    async fn write_response<W: AsyncWrite>(
+        &self,
+        url: &Url,
+        resp: Response,
+        mut writer: HashWriter<W>,
+    ) -> Result<HashWriter<W>, Error> {
+        let status = resp.status();
+        if !status.is_success() {
+            bail!(
+                "failed to download with status {status}: {url}\n=== body ===\n{}\n============\n",
+                http::body_snippet(resp).await
+            );
+        }
+
+        let bar = self.progress_bar(url, resp.content_length());
+        // Throttling the chunks makes the stream hold a future, which must be pinned to be read.
+        let stream = std::pin::pin!(
+            resp.bytes_stream()
+                .inspect_ok(|chunk| {
+                    if let Some(bar) = &bar {
+                        bar.inc(chunk.len() as u64);
+                    }
+                })
+                .and_then(|chunk| async move {
+                    if let Some(throttle) = &self.throttle {
+                        throttle.consume(chunk.len()).await;
+                    }
+                    Ok(chunk)
+                })
+                .map_err(std::io::Error::other)
+        );
+        let result = tokio::io::copy(&mut StreamReader::new(stream), &mut writer).await;
+        if let Some(bar) = &bar {
+            bar.finish_and_clear();
+        }
+        result.map_err(|e| http::map_io_err(e, url.as_str()))?;
+        Ok(writer)
+    }
+
+    /// Create a progress bar for the download of `url`, if progress bars are shown.
+    fn progress_bar(&self, url: &Url, len: Option<u64>) -> Option<ProgressBar> {
+        let bar = match len {
+            Some(len) => ProgressBar::new(len),
+            None => ProgressBar::no_length(),
+        };
+        let bar = self.progress.as_ref()?.add(bar);
+        bar.set_style(
+            ProgressStyle::with_template(PROGRESS_TEMPLATE)
+                .expect("invalid progress bar template")
+                .progress_chars("=> "),
+        );
+        bar.set_message(
+            url.path()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        );
+        Some(bar)
+    }
+
+    /// Path the file is downloaded to. When the storage is compressed, the file is only there
+    /// until it's verified, and is then at [`Downloader::compressed_path_for`].
     pub(crate) fn path_for(&self, file: &MirrorFile) -> PathBuf {
-        self.storage.path().join(&file.sha256)
+        self.storage_path().join(&file.sha256)
     }
+
+    /// Path of the downloaded file once compressed, or `None` if the storage isn't compressed.
+    pub(crate) fn compressed_path_for(&self, file: &MirrorFile) -> Option<PathBuf> {
+        self.compress_storage
+            .then(|| self.storage_path().join(format!("{}.zst", file.sha256)))
+    }
+
+    fn storage_path(&self) -> &Path {
+        self.storage
+            .as_ref()
+            .expect("hashing-only downloaders don't store files")
+            .path()
+    }
+}
+
+/// Decompress a file stored compressed by the downloader to a temporary file next to it, which is
+/// deleted when the returned path is dropped.
+pub(crate) async fn decompress_stored(compressed: PathBuf) -> Result<TempPath, Error> {
+    tokio::task::spawn_blocking(move || {
+        let dir = compressed
+            .parent()
+            .expect("stored files are in a directory");
+        let mut output = NamedTempFile::new_in(dir)?;
+        let input = std::fs::File::open(&compressed)?;
+        zstd::stream::copy_decode(input, std::io::BufWriter::new(output.as_file_mut()))?;
+        Ok::<_, Error>(output.into_temp_path())
+    })
+    .await?
+    .context("failed to decompress the downloaded file")
 }
 
-fn format_size(size: usize) -> String {
+/// Limits the aggregate speed of the downloads, by spacing out the chunks received by all of them
+/// so that the bandwidth is never exceeded on average.
+struct Throttle {
+    bytes_per_sec: f64,
+    /// When the bandwidth used by the chunks received so far is freed. This uses the Tokio clock,
+    /// so that the tests can pause it.
+    next_free: Mutex<tokio::time::Instant>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1) as f64,
+            next_free: Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Wait until the bandwidth used by a chunk of `len` bytes is freed, after the one used by
+    /// the previous chunks.
+    async fn consume(&self, len: usize) {
+        let delay = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = tokio::time::Instant::now();
+            *next_free =
+                (*next_free).max(now) + Duration::from_secs_f64(len as f64 / self.bytes_per_sec);
+            *next_free - now
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Compute the hash of a local file, the same way [`Downloader::get_file_hash`] does for
+/// downloaded files.
+pub(crate) async fn get_local_file_hash(
+    path: &Path,
+    decompress: Option<Compression>,
+) -> Result<Sha256, Error> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut writer = HashWriter::new(tokio::io::sink(), decompress, false)?;
+    tokio::io::copy(&mut file, &mut writer)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(writer.finish()?.content)
+}
+
+pub(crate) fn format_size(size: usize) -> String {
     let mut size = size as f64;
     for unit in ["bytes", "kB", "MB", "GB"] {
         if size / 1000.0 < 1.0 {
@@ -99,33 +637,59 @@ fn format_size(size: usize) -> String {
     format!("{size:.2} TB")
 }
 
-pub struct Sha256Writer<W: AsyncWrite> {
+/// Writes the downloaded data, while hashing it with SHA-256 and, when requested, SHA-512, so
+/// that the file is read only once.
+pub struct HashWriter<W: AsyncWrite> {
     sha256: Sha256,
+    sha512: Option<Sha512>,
+    decompressor: Option<Decompressor>,
     len: usize,
     writer: Pin<Box<W>>,
 }
 
-impl<W: AsyncWrite> Sha256Writer<W> {
-    fn new(writer: W) -> Self {
-        Self {
+impl<W: AsyncWrite> HashWriter<W> {
+    fn new(
+        writer: W,
+        decompress: Option<Compression>,
+        sha512: bool,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Self {
             sha256: Sha256::new(),
+            sha512: sha512.then(Sha512::new),
+            decompressor: decompress.map(Decompressor::new).transpose()?,
             len: 0,
             writer: Box::pin(writer),
-        }
+        })
+    }
+
+    fn finish(self) -> Result<Hashes, std::io::Error> {
+        Ok(Hashes {
+            len: self.len,
+            content: match self.decompressor {
+                Some(decompressor) => decompressor.finish()?,
+                None => self.sha256.clone(),
+            },
+            raw: self.sha256,
+            sha512: self.sha512,
+        })
     }
 }
 
-impl<W: AsyncWrite> AsyncWrite for Sha256Writer<W> {
+impl<W: AsyncWrite> AsyncWrite for HashWriter<W> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        use sha2::Digest;
-
         match self.writer.as_mut().poll_write(cx, buf) {
             Poll::Ready(Ok(written)) => {
                 self.sha256.update(&buf[..written]);
+                if let Some(sha512) = &mut self.sha512 {
+                    sha512.update(&buf[..written]);
+                }
+                if let Some(decompressor) = &mut self.decompressor {
+                    decompressor.write_all(&buf[..written])?;
+                }
                 self.len += written;
                 Poll::Ready(Ok(written))
             }
@@ -147,3 +711,195 @@ impl<W: AsyncWrite> AsyncWrite for Sha256Writer<W> {
         self.writer.as_mut().poll_shutdown(cx)
     }
 }
+
+pub(crate) struct Hashes {
+    /// Number of downloaded bytes.
+    pub(crate) len: usize,
+    /// Hash of the downloaded bytes.
+    pub(crate) raw: Sha256,
+    /// Hash of the content of the file, after decompressing it if needed.
+    pub(crate) content: Sha256,
+    /// SHA-512 of the downloaded bytes, only computed for files with a `sha512`.
+    pub(crate) sha512: Option<Sha512>,
+}
+
+/// Decompresses the written data in memory to hash it, while the original data is written to
+/// disk, so that the compressed file can be mirrored.
+enum Decompressor {
+    Gzip(flate2::write::GzDecoder<Sha256>),
+    Xz(xz2::write::XzDecoder<Sha256>),
+    Zstd(zstd::stream::write::Decoder<'static, Sha256>),
+}
+
+impl Decompressor {
+    fn new(compression: Compression) -> Result<Self, std::io::Error> {
+        Ok(match compression {
+            Compression::Gzip => Decompressor::Gzip(flate2::write::GzDecoder::new(Sha256::new())),
+            Compression::Xz => Decompressor::Xz(xz2::write::XzDecoder::new(Sha256::new())),
+            Compression::Zstd => {
+                Decompressor::Zstd(zstd::stream::write::Decoder::new(Sha256::new())?)
+            }
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            Decompressor::Gzip(decoder) => decoder.write_all(data),
+            Decompressor::Xz(decoder) => decoder.write_all(data),
+            Decompressor::Zstd(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn finish(self) -> Result<Sha256, std::io::Error> {
+        match self {
+            Decompressor::Gzip(decoder) => decoder.finish(),
+            Decompressor::Xz(mut decoder) => decoder.finish(),
+            Decompressor::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Source;
+    use crate::test_utils::{HELLO_SHA256, MockServer, mirror_file, sdk_config};
+
+    /// SHA-512 of `hello`.
+    const HELLO_SHA512: &str = "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca7\
+                                2323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043";
+
+    fn s3_downloader(server: &MockServer) -> Downloader {
+        let http = HttpArgs {
+            allow_s3_source_buckets: vec!["allowed".into()],
+            ..HttpArgs::default()
+        };
+        let downloader = Downloader::hashing_only(&http)
+            .unwrap()
+            .s3_endpoint_url(Some(server.url("")));
+        let client = downloader.s3_client(&sdk_config());
+        downloader.s3.set(client).unwrap();
+        downloader
+    }
+
+    #[tokio::test]
+    async fn s3_source_from_allowed_bucket() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = s3_downloader(&server);
+        let url = Url::parse("s3://allowed/dir/file.tar.gz").unwrap();
+        let hash = downloader.get_file_hash(&url, None, false).await.unwrap();
+        assert_eq!(to_hex(&hash), HELLO_SHA256);
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /allowed/dir/file.tar.gz"));
+    }
+
+    #[tokio::test]
+    async fn s3_source_from_other_bucket() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = s3_downloader(&server);
+        let url = Url::parse("s3://other/file.tar.gz").unwrap();
+        let error = downloader
+            .get_file_hash(&url, None, false)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("--allow-s3-source-bucket other"));
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn single_hash() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = Downloader::hashing_only(&HttpArgs::default()).unwrap();
+        let file = mirror_file("hello", HELLO_SHA256, &server.url("hello"));
+        let hashes = downloader.check(&file).await.unwrap();
+        assert_eq!(to_hex(&hashes.content), HELLO_SHA256);
+        assert!(hashes.sha512.is_none());
+    }
+
+    #[tokio::test]
+    async fn dual_hash() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = Downloader::hashing_only(&HttpArgs::default()).unwrap();
+        let mut file = mirror_file("hello", HELLO_SHA256, &server.url("hello"));
+        file.sha512 = Some(HELLO_SHA512.into());
+        let hashes = downloader.check(&file).await.unwrap();
+        assert_eq!(hashes.sha512.as_ref().map(to_hex).unwrap(), HELLO_SHA512);
+    }
+
+    #[tokio::test]
+    async fn dual_hash_with_wrong_sha512() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = Downloader::hashing_only(&HttpArgs::default()).unwrap();
+        let mut file = mirror_file("hello", HELLO_SHA256, &server.url("hello"));
+        file.sha512 = Some("0".repeat(128));
+        let error = downloader.check(&file).await.err().unwrap();
+        assert!(error.to_string().contains("the SHA-512 of"));
+    }
+
+    #[tokio::test]
+    async fn git_source_from_archive() {
+        let server = MockServer::start(200, b"hello");
+        let downloader = Downloader::hashing_only(&HttpArgs::default()).unwrap();
+        let mut file = mirror_file("libc.tar.gz", HELLO_SHA256, &server.url("unused"));
+        file.source = Source::Git {
+            repo: Url::parse("https://github.com/rust-lang/libc").unwrap(),
+            rev: "0.2.170".into(),
+            archive: Url::parse(&server.url("rust-lang/libc/archive/0.2.170.tar.gz")).unwrap(),
+        };
+        let hashes = downloader.check(&file).await.unwrap();
+        assert_eq!(to_hex(&hashes.content), HELLO_SHA256);
+        assert!(server.requests()[0].starts_with("GET /rust-lang/libc/archive/0.2.170.tar.gz "));
+    }
+
+    #[tokio::test]
+    async fn decompressed_xz_hash() {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(b"hello").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let server = MockServer::start(200, &compressed);
+        let downloader = Downloader::hashing_only(&HttpArgs::default()).unwrap();
+        let mut file = mirror_file("hello.xz", HELLO_SHA256, &server.url("hello.xz"));
+        file.decompress = Some(Compression::Xz);
+        let hashes = downloader.check(&file).await.unwrap();
+        assert_eq!(to_hex(&hashes.content), HELLO_SHA256);
+        let mut raw = Sha256::new();
+        raw.update(&compressed);
+        assert_eq!(to_hex(&hashes.raw), to_hex(&raw));
+        assert_eq!(hashes.len, compressed.len());
+
+        file.decompress = None;
+        let error = downloader.check(&file).await.err().unwrap();
+        assert!(error.to_string().starts_with("the hash of "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_shared_by_concurrent_downloads() {
+        const BYTES_PER_SEC: u64 = 1000;
+        const CHUNK: usize = 100;
+        const CHUNKS_PER_TASK: usize = 10;
+
+        let throttle = Arc::new(Throttle::new(BYTES_PER_SEC));
+        let start = tokio::time::Instant::now();
+        let tasks = (0..2)
+            .map(|_| {
+                let throttle = throttle.clone();
+                tokio::spawn(async move {
+                    for _ in 0..CHUNKS_PER_TASK {
+                        throttle.consume(CHUNK).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let total = 2 * CHUNKS_PER_TASK * CHUNK;
+        let expected = Duration::from_secs_f64(total as f64 / BYTES_PER_SEC as f64);
+        assert!(start.elapsed() >= expected, "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::{Context as _, Error, bail};
+use reqwest::Url;
+use std::io::{Read, Write};
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use suppaftp::native_tls::TlsConnector;
+use suppaftp::types::FileType;
+use suppaftp::{FtpError, FtpStream, NativeTlsConnector, NativeTlsFtpStream};
+
+const DEFAULT_PORT: u16 = 21;
+
+/// Download the file at the `ftp://` or `ftps://` URL, writing its contents into `dest`. The
+/// `ftps` scheme uses explicit TLS (`AUTH TLS`) on the standard FTP port.
+///
+/// This uses blocking IO, and should be called from a blocking task.
+pub(crate) fn retrieve(
+    url: &Url,
+    connect_timeout: Duration,
+    mut dest: impl Write,
+) -> Result<(), Error> {
+    let host = url
+        .host_str()
+        .with_context(|| format!("missing host in {url}"))?;
+    let addr = (host, url.port().unwrap_or(DEFAULT_PORT))
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {host}"))?
+        .next()
+        .with_context(|| format!("no addresses found for {host}"))?;
+    let path = percent_encoding::percent_decode_str(url.path()).decode_utf8()?;
+
+    let (user, password) = match url.username() {
+        "" => ("anonymous", "anonymous"),
+        user => (user, url.password().unwrap_or_default()),
+    };
+
+    let mut copy = |reader: &mut dyn Read| -> Result<(), FtpError> {
+        std::io::copy(reader, &mut dest).map_err(FtpError::ConnectionError)?;
+        Ok(())
+    };
+
+    match url.scheme() {
+        "ftp" => {
+            let mut ftp = FtpStream::connect_timeout(addr, connect_timeout)?;
+            ftp.login(user, password)?;
+            ftp.transfer_type(FileType::Binary)?;
+            ftp.retr(&path, &mut copy)?;
+            ftp.quit()?;
+        }
+        "ftps" => {
+            let mut ftp = NativeTlsFtpStream::connect_timeout(addr, connect_timeout)?
+                .into_secure(NativeTlsConnector::from(TlsConnector::new()?), host)?;
+            ftp.login(user, password)?;
+            ftp.transfer_type(FileType::Binary)?;
+            ftp.retr(&path, &mut copy)?;
+            ftp.quit()?;
+        }
+        scheme => bail!("unsupported URL scheme `{scheme}`: {url}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead as _, BufReader};
+    use std::net::TcpListener;
+
+    /// Minimal FTP server serving `content` for any file, over a single passive connection.
+    fn serve(content: &'static [u8]) -> Url {
+        let control = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = control.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = control.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            let data = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = data.local_addr().unwrap().port();
+            writeln!(stream, "220 ready\r").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let reply = match line.split_whitespace().next().unwrap_or_default() {
+                    "USER" => "331 password required".to_string(),
+                    "PASS" => "230 logged in".to_string(),
+                    "TYPE" => "200 type set".to_string(),
+                    "PASV" => format!(
+                        "227 Entering Passive Mode (127,0,0,1,{},{})",
+                        port >> 8,
+                        port & 0xff
+                    ),
+                    "RETR" => {
+                        writeln!(stream, "150 sending\r").unwrap();
+                        let (mut data, _) = data.accept().unwrap();
+                        data.write_all(content).unwrap();
+                        drop(data);
+                        "226 done".to_string()
+                    }
+                    "QUIT" => {
+                        writeln!(stream, "221 bye\r").unwrap();
+                        break;
+                    }
+                    _ => "502 not implemented".to_string(),
+                };
+                writeln!(stream, "{reply}\r").unwrap();
+            }
+        });
+        Url::parse(&format!("ftp://{addr}/pub/file.tar.gz")).unwrap()
+    }
+
+    #[test]
+    fn retrieve_anonymous() {
+        let url = serve(b"hello");
+        let mut dest = Vec::new();
+        retrieve(&url, Duration::from_secs(5), &mut dest).unwrap();
+        assert_eq!(dest, b"hello");
+    }
+}
@@ -0,0 +1,92 @@
+use crate::utils::OutputFormat;
+use anyhow::Error;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Outcome of a HEAD request to a URL.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Probe {
+    Status(u16),
+    Error(String),
+    /// The URL can't be checked with a HEAD request, like FTP URLs.
+    Unsupported,
+}
+
+impl Probe {
+    /// Whether the URL was checked and isn't reachable. URLs that can't be checked are not
+    /// reported, as nothing can be said about them.
+    pub(crate) fn is_broken(&self) -> bool {
+        !matches!(self, Probe::Status(200..=299) | Probe::Unsupported)
+    }
+}
+
+impl From<Result<Option<StatusCode>, Error>> for Probe {
+    fn from(result: Result<Option<StatusCode>, Error>) -> Self {
+        match result {
+            Ok(Some(status)) => Probe::Status(status.as_u16()),
+            Ok(None) => Probe::Unsupported,
+            Err(err) => Probe::Error(format!("{err:#}")),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Status(status) => write!(f, "{status}"),
+            Probe::Error(err) => write!(f, "error: {err}"),
+            Probe::Unsupported => f.write_str("unsupported"),
+        }
+    }
+}
+
+/// Reachability of a mirrored file, both on its upstream and on the CDN.
+#[derive(Serialize)]
+pub(crate) struct Health {
+    pub(crate) name: String,
+    pub(crate) upstream: Probe,
+    pub(crate) cdn: Probe,
+}
+
+/// Render the health of the files, sorted by name. The text output is a table with one file per
+/// line.
+pub(crate) fn render(entries: &mut [Health], format: OutputFormat) -> Result<String, Error> {
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    match format {
+        OutputFormat::Json => {
+            let mut output = serde_json::to_string_pretty(&entries)?;
+            output.push('\n');
+            Ok(output)
+        }
+        OutputFormat::Text => {
+            let rows = entries
+                .iter()
+                .map(|entry| {
+                    [
+                        entry.name.clone(),
+                        entry.upstream.to_string(),
+                        entry.cdn.to_string(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let header = ["NAME".to_string(), "UPSTREAM".into(), "CDN".into()];
+            let name_width = rows.iter().chain([&header]).map(|row| row[0].len()).max();
+            let upstream_width = rows.iter().chain([&header]).map(|row| row[1].len()).max();
+
+            let mut output = String::new();
+            for [name, upstream, cdn] in std::iter::once(&header).chain(&rows) {
+                writeln!(
+                    output,
+                    "{name:<name_width$}  {upstream:<upstream_width$}  {cdn}",
+                    name_width = name_width.unwrap_or_default(),
+                    upstream_width = upstream_width.unwrap_or_default(),
+                )
+                .unwrap();
+            }
+            Ok(output)
+        }
+    }
+}
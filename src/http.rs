@@ -0,0 +1,238 @@
+use anyhow::Error;
+use clap::Parser;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Response, Url};
+use std::fmt;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const DEFAULT_USER_AGENT: &str = concat!(
+    "rust-lang-ci-mirrors/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/rust-lang/ci-mirrors)"
+);
+const MAX_BODY_SNIPPET: usize = 4096;
+const DEFAULT_TIMEOUT: u64 = 300;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+const MAX_REDIRECTS: usize = 10;
+const DEFAULT_PER_HOST_JOBS: usize = 4;
+
+/// Configuration of the HTTP client used to download files and to query the CDN.
+#[derive(Debug, Clone, Parser)]
+pub struct HttpArgs {
+    /// Maximum time (in seconds) an HTTP request can take, including downloading the body.
+    #[arg(long, default_value_t = DEFAULT_TIMEOUT)]
+    pub http_timeout: u64,
+
+    /// Maximum time (in seconds) to wait for an HTTP connection to be established.
+    #[arg(long, default_value_t = DEFAULT_CONNECT_TIMEOUT)]
+    pub http_connect_timeout: u64,
+
+    /// Warn when a download is slower than this speed (in kB/s).
+    #[arg(long)]
+    pub min_download_speed: Option<usize>,
+
+    /// User-Agent sent with every HTTP request, identifying the tool to the upstreams.
+    #[arg(long, env = "CI_MIRRORS_USER_AGENT", default_value = DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+
+    /// Maximum number of concurrent downloads from the same upstream host, to avoid tripping
+    /// its rate limits. Downloads from different hosts are only limited by `--jobs`.
+    #[arg(long, default_value_t = DEFAULT_PER_HOST_JOBS)]
+    pub per_host_jobs: usize,
+
+    /// Allow downloading `file://` URLs from the local filesystem, for testing or to mirror
+    /// artifacts built locally. This should never be enabled in CI.
+    #[arg(long)]
+    pub allow_file_urls: bool,
+
+    /// Allow downloading `s3://` sources from this bucket, with the AWS credentials of the
+    /// environment. Can be repeated. Other buckets are rejected, so that the credentials used to
+    /// upload can't be used to publish arbitrary objects on the CDN.
+    #[arg(long = "allow-s3-source-bucket")]
+    pub allow_s3_source_buckets: Vec<String>,
+
+    /// Maximum download speed (in bytes per second), shared by all the concurrent HTTP
+    /// downloads, to avoid saturating the network of shared runners.
+    #[arg(long)]
+    pub max_bandwidth: Option<u64>,
+}
+
+impl Default for HttpArgs {
+    fn default() -> Self {
+        Self {
+            http_timeout: DEFAULT_TIMEOUT,
+            http_connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            min_download_speed: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            per_host_jobs: DEFAULT_PER_HOST_JOBS,
+            allow_file_urls: false,
+            allow_s3_source_buckets: Vec::new(),
+            max_bandwidth: None,
+        }
+    }
+}
+
+impl HttpArgs {
+    pub(crate) fn client(&self) -> Result<Client, Error> {
+        Ok(Client::builder()
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout())
+            .timeout(Duration::from_secs(self.http_timeout))
+            .redirect(redirect_policy())
+            .build()?)
+    }
+
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_connect_timeout)
+    }
+
+    /// Minimum expected download throughput, in bytes per second.
+    pub(crate) fn min_throughput(&self) -> Option<usize> {
+        self.min_download_speed.map(|speed| speed * 1000)
+    }
+}
+
+/// Follow a bounded number of redirects, refusing to downgrade from HTTPS to HTTP. Redirects to a
+/// different host are allowed (download links often point to a separate storage host), but
+/// reported, as they change where the mirrored content comes from.
+fn redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        let original = attempt.previous()[0].clone();
+        let chain = attempt
+            .previous()
+            .iter()
+            .chain(std::iter::once(attempt.url()))
+            .map(Url::as_str)
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        debug!("following redirect: {chain}");
+
+        if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error(format!("too many redirects: {chain}"))
+        } else if original.scheme() == "https" && attempt.url().scheme() != "https" {
+            attempt.error(format!(
+                "refusing to follow a redirect away from HTTPS: {chain}"
+            ))
+        } else {
+            if attempt.url().host_str() != original.host_str() {
+                warn!("redirected to a different host: {chain}");
+            }
+            attempt.follow()
+        }
+    })
+}
+
+/// Error returned when an HTTP request didn't complete in time. Timeouts are usually caused by
+/// transient network issues, so callers can downcast to this type to decide whether to retry.
+#[derive(Debug)]
+pub(crate) struct TimeoutError {
+    url: String,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request to {} timed out (this is likely a transient error, retrying might help)",
+            self.url
+        )
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Convert a reqwest error into an anyhow error, turning timeouts into [`TimeoutError`].
+pub(crate) fn map_err(err: reqwest::Error, url: &str) -> Error {
+    if err.is_timeout() {
+        TimeoutError {
+            url: url.to_owned(),
+        }
+        .into()
+    } else {
+        err.into()
+    }
+}
+
+/// Same as [`map_err`], but for errors returned while streaming the response body.
+pub(crate) fn map_io_err(err: std::io::Error, url: &str) -> Error {
+    match err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+    {
+        Some(inner) if inner.is_timeout() => TimeoutError {
+            url: url.to_owned(),
+        }
+        .into(),
+        _ => err.into(),
+    }
+}
+
+/// Read the start of the response body to include it in error messages. Only the first few KB
+/// are read, as error responses can be arbitrarily large.
+pub(crate) async fn body_snippet(mut resp: Response) -> String {
+    let mut body = Vec::new();
+    let mut truncated = false;
+    // Errors while reading the body are ignored, as the snippet is only used for diagnostics.
+    while let Ok(Some(chunk)) = resp.chunk().await {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_BODY_SNIPPET {
+            body.truncate(MAX_BODY_SNIPPET);
+            truncated = true;
+            break;
+        }
+    }
+
+    let mut snippet = String::from_utf8_lossy(&body).into_owned();
+    if truncated {
+        snippet.push_str("\n[...truncated]");
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockServer;
+
+    /// Value of the `User-Agent` header of the first request received by `server`.
+    fn user_agent(server: &MockServer) -> String {
+        let request = server.requests().remove(0);
+        request
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("user-agent")
+                    .then(|| value.trim().to_string())
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn default_user_agent() {
+        let server = MockServer::start(200, b"");
+        let client = HttpArgs::default().client().unwrap();
+        client.get(server.url("file")).send().await.unwrap();
+        let user_agent = user_agent(&server);
+        assert_eq!(user_agent, DEFAULT_USER_AGENT);
+        assert!(
+            user_agent.starts_with(concat!("rust-lang-ci-mirrors/", env!("CARGO_PKG_VERSION")))
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_user_agent() {
+        let server = MockServer::start(200, b"");
+        let args = HttpArgs {
+            user_agent: "custom-mirror/1.0".into(),
+            ..HttpArgs::default()
+        };
+        args.client()
+            .unwrap()
+            .get(server.url("file"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(user_agent(&server), "custom-mirror/1.0");
+    }
+}
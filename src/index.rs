@@ -0,0 +1,86 @@
+use crate::manifest::{MirrorFile, Source};
+use anyhow::{Context as _, Error};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct IndexEntry<'a> {
+    name: &'a str,
+    sha256: &'a str,
+    source: Option<&'a str>,
+    license: Option<&'a str>,
+    legacy: bool,
+}
+
+/// Render the JSON index of all the mirrored files. Entries are sorted by name, so that the
+/// output only changes when the manifests change.
+pub(crate) fn render(files: &[MirrorFile]) -> Result<String, Error> {
+    let mut entries = files
+        .iter()
+        .map(|file| IndexEntry {
+            name: &file.name,
+            sha256: &file.sha256,
+            source: file.source.url().map(Url::as_str),
+            license: file.license.as_deref(),
+            legacy: matches!(file.source, Source::Legacy),
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.name);
+
+    let mut output = serde_json::to_string_pretty(&entries)?;
+    output.push('\n');
+    Ok(output)
+}
+
+/// Entry of an index written by [`render`], with only the fields needed to find the files with
+/// the same content.
+#[derive(Deserialize)]
+struct IndexedFile {
+    name: String,
+    sha256: String,
+}
+
+/// Load the index at `path`, returning the names of the indexed files keyed by their hash.
+pub(crate) fn load_names_by_hash(path: &Path) -> Result<HashMap<String, Vec<String>>, Error> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let entries: Vec<IndexedFile> = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let mut names = HashMap::<_, Vec<_>>::new();
+    for entry in entries {
+        names.entry(entry.sha256).or_default().push(entry.name);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{HELLO_SHA256, mirror_file};
+
+    #[test]
+    fn render_golden() {
+        let mut legacy = mirror_file("libc/old.tar.gz", HELLO_SHA256, "https://example.com");
+        legacy.source = Source::Legacy;
+        legacy.license = None;
+        let mut gcc = mirror_file(
+            "gcc/gcc-9.5.0.tar.xz",
+            "27769f64ef1d4cd5e2be8682c0c93f9887983e6cfd1a927ce5a0a2915a95cf8f",
+            "https://ftp.gnu.org/gnu/gcc/gcc-9.5.0/gcc-9.5.0.tar.xz",
+        );
+        gcc.license = Some("GPL-3.0-or-later".into());
+
+        // The entries are sorted by name, whatever the order of the manifests.
+        let output = render(&[legacy, gcc]).unwrap();
+        assert_eq!(output, include_str!("../tests/fixtures/index.json"));
+    }
+
+    #[test]
+    fn load_golden() {
+        let names = load_names_by_hash(Path::new("tests/fixtures/index.json")).unwrap();
+        assert_eq!(names[HELLO_SHA256], ["libc/old.tar.gz"]);
+        assert_eq!(names.len(), 2);
+    }
+}
@@ -0,0 +1,39 @@
+//! Tooling to manage the contents of [ci-mirrors.rust-lang.org](https://ci-mirrors.rust-lang.org).
+//!
+//! [`Mirror`] checks and uploads the mirrored files defined in the manifests, while [`run_cli`]
+//! runs the command line interface implemented on top of it.
+
+mod archive;
+mod audit;
+mod cli;
+mod config;
+mod diff;
+mod doctor;
+mod downloader;
+mod ftp;
+mod health;
+mod http;
+mod index;
+mod listing;
+mod manifest;
+mod mirror;
+mod run_report;
+mod signature;
+mod status_cache;
+mod storage;
+#[cfg(test)]
+mod test_utils;
+mod utils;
+
+pub use crate::http::HttpArgs;
+pub use crate::manifest::{
+    ArchiveFormat, Compression, ErrorLocation, LoadOptions, MirrorFile, Signature, SignatureKey,
+    Source, ValidationError, ValidationErrorKind,
+};
+pub use crate::mirror::{Mirror, MirrorConfig, Plan, PlanError, PlanErrorKind, Progress};
+
+/// Run the command line interface with the arguments of the process, setting up logging. This is
+/// the entry point of the `ci-mirrors` binary.
+pub async fn run_cli() -> Result<(), anyhow::Error> {
+    cli::run(cli::Cli::parse_with_config()?).await
+}
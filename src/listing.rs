@@ -0,0 +1,151 @@
+use crate::utils::is_sha256_hex;
+use anyhow::{Context as _, Error, bail};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+/// Characters to escape when turning a file name into a relative URL.
+const PATH_SEGMENT_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b':')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Format of an upstream directory listing.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum ListingFormat {
+    /// HTML page generated by the autoindex modules of Apache or nginx.
+    Autoindex,
+    /// JSON generated by nginx with `autoindex_format json`.
+    NginxJson,
+}
+
+impl ListingFormat {
+    /// Extract the URLs of the files listed in `body`, the listing of the directory at `base`.
+    /// Subdirectories and links outside of the directory are ignored.
+    pub(crate) fn parse(self, base: &Url, body: &str) -> Result<Vec<Url>, Error> {
+        let links = match self {
+            ListingFormat::Autoindex => autoindex_links(body),
+            ListingFormat::NginxJson => nginx_json_links(body)?,
+        };
+
+        let mut files = BTreeSet::new();
+        for link in links {
+            // Sorting links are only query strings, and are skipped here too.
+            if link.starts_with(['?', '#']) || link.ends_with('/') {
+                continue;
+            }
+            let url = base
+                .join(&link)
+                .with_context(|| format!("invalid link {link:?} in the listing of {base}"))?;
+            if url.query().is_none()
+                && url.fragment().is_none()
+                && let Some(name) = url.as_str().strip_prefix(base.as_str())
+                && !name.is_empty()
+                && !name.contains('/')
+            {
+                files.insert(url);
+            }
+        }
+        Ok(files.into_iter().collect())
+    }
+}
+
+/// Extract the targets of all the `href` attributes. Autoindex pages are simple enough that
+/// parsing the whole HTML is not needed.
+fn autoindex_links(body: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + "href=".len()..];
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        rest = &rest[1..];
+        let Some(end) = rest.find(quote) else {
+            break;
+        };
+        links.push(unescape_html(&rest[..end]));
+        rest = &rest[end + 1..];
+    }
+    links
+}
+
+/// A file listed in a checksums file.
+pub(crate) struct Checksum {
+    pub(crate) name: String,
+    pub(crate) url: Url,
+    pub(crate) sha256: String,
+}
+
+/// Parse a checksums file in the format of `sha256sum`, with one `<hash>  <file name>` line per
+/// file, resolving the file names relative to `base`. Files in subdirectories are ignored.
+pub(crate) fn parse_checksums(base: &Url, body: &str) -> Result<Vec<Checksum>, Error> {
+    let mut checksums = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((hash, name)) = line.split_once(' ') else {
+            bail!("invalid line {} in the checksums file: {line:?}", index + 1);
+        };
+        let hash = hash.to_ascii_lowercase();
+        // The name is prefixed with `*` or a space, depending on the mode used to read the file.
+        let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+        let name = name.strip_prefix("./").unwrap_or(name);
+        if !is_sha256_hex(&hash) {
+            bail!(
+                "invalid SHA-256 hash on line {} of the checksums file: {hash:?}",
+                index + 1
+            );
+        }
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+        let link = utf8_percent_encode(name, PATH_SEGMENT_ESCAPE).to_string();
+        checksums.push(Checksum {
+            name: name.to_string(),
+            url: base
+                .join(&link)
+                .with_context(|| format!("invalid file name {name:?} in the checksums file"))?,
+            sha256: hash,
+        });
+    }
+    Ok(checksums)
+}
+
+fn unescape_html(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn nginx_json_links(body: &str) -> Result<Vec<String>, Error> {
+    #[derive(Deserialize)]
+    struct Entry {
+        name: String,
+        #[serde(rename = "type")]
+        kind: String,
+    }
+
+    let entries: Vec<Entry> =
+        serde_json::from_str(body).context("failed to parse the nginx JSON listing")?;
+    Ok(entries
+        .into_iter()
+        .filter(|entry| entry.kind == "file")
+        .map(|entry| utf8_percent_encode(&entry.name, PATH_SEGMENT_ESCAPE).to_string())
+        .collect())
+}
@@ -1,10 +1,14 @@
-use anyhow::{Context, Error};
+use crate::utils::is_sha512_hex;
+use anyhow::{Context, Error, bail};
 use reqwest::Url;
+use schemars::JsonSchema;
 use serde::de::Error as _;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::num::NonZero;
 use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 #[derive(PartialEq, Eq, Clone)]
 struct Span(std::ops::Range<usize>);
@@ -36,138 +40,872 @@ struct LocationCache {
     seen_urls: HashMap<Url, BTreeSet<Location>>,
     seen_hashes: HashMap<String, BTreeSet<Location>>,
     seen_paths: HashMap<String, BTreeSet<Location>>,
+    /// Entries marked with `skip-validation`, which are excluded from the detection of duplicates
+    /// but can still be pointed at by the other errors.
+    skipped_paths: HashMap<String, BTreeSet<Location>>,
 }
 
+#[derive(Default)]
+pub struct LoadOptions {
+    /// Rewrite the manifests to automatically fix the errors that can be fixed.
+    pub fix: bool,
+    /// Report managed entries with an empty `license` as errors.
+    pub require_license: bool,
+    /// Report entries downloaded from a URL or git repository over plain HTTP as errors rather
+    /// than warnings.
+    pub deny_insecure_urls: bool,
+    /// Report managed entries named with one of these extensions as errors, unless they are
+    /// marked with `allow-extension = true`.
+    pub denied_extensions: Vec<String>,
+    /// When not empty, report managed entries not named with one of these extensions as errors,
+    /// unless they are marked with `allow-extension = true`.
+    pub allowed_extensions: Vec<String>,
+    /// Also report entries whose CDN paths only differ by case, for CDNs ignoring it. S3 keys
+    /// are case-sensitive, so these entries are otherwise stored as separate objects.
+    pub case_insensitive_paths: bool,
+    /// Don't warn when there are no manifests to load, which usually means that the path of the
+    /// manifests is wrong.
+    pub allow_empty: bool,
+}
+
+impl LoadOptions {
+    /// Error to report if the extension of `name` is not allowed.
+    fn extension_error(&self, name: &str) -> Option<String> {
+        let name = name.to_ascii_lowercase();
+        let has_extension = |extension: &String| {
+            let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            name.ends_with(&format!(".{extension}"))
+        };
+        if let Some(extension) = self.denied_extensions.iter().find(|e| has_extension(e)) {
+            Some(format!(
+                "Files with the `.{}` extension cannot be mirrored without \
+                 `allow-extension = true`",
+                extension.trim_start_matches('.')
+            ))
+        } else if !self.allowed_extensions.is_empty()
+            && !self.allowed_extensions.iter().any(has_extension)
+        {
+            Some(
+                "The extension of the file is not allowed, add `allow-extension = true` to \
+                 mirror it anyway"
+                    .to_string(),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Result of parsing a manifest: its content along with the parsed manifest.
+type Parsed = Result<(String, Manifest), ValidationError>;
+
+/// Loads either a single manifest, or all the manifests in a directory (recursively).
 /// Returns (files, found errors).
 /// Errors are returned explicitly so that they can be merged with follow-up errors, rather than
 /// exiting immediately.
-pub(crate) fn load_manifests(load_from: &Path) -> Result<(Vec<MirrorFile>, Vec<String>), Error> {
+pub(crate) fn load_manifests(
+    load_from: &Path,
+    options: &LoadOptions,
+) -> Result<(Vec<MirrorFile>, Vec<ValidationError>), Error> {
+    if !load_from.exists() {
+        bail!(
+            "{} doesn't exist, check the path of the manifests",
+            load_from.display()
+        );
+    }
+    let paths = manifest_paths(load_from)?;
+    if paths.is_empty() && !options.allow_empty {
+        warn!(
+            "{} doesn't contain any manifest, check the path of the manifests \
+             (pass --allow-empty if this is expected)",
+            load_from.display()
+        );
+    }
+
+    // Parse all the manifests first, so that a malformed one doesn't hide the errors of the
+    // others. Parsing is spread across threads, while the entries are then loaded in the order of
+    // the paths, so that the errors don't depend on which manifest was parsed first.
+    let threads = std::thread::available_parallelism().map_or(1, NonZero::get);
+    let chunk_size = paths.len().div_ceil(threads).max(1);
+    let parsed = std::thread::scope(|scope| {
+        let handles = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| parse_file(path))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    load_parsed(paths.into_iter().zip(parsed).collect(), options)
+}
+
+/// Loads a single manifest from its content rather than from disk, for example when it's piped
+/// through stdin. `name` is only used to refer to the manifest in the errors.
+pub(crate) fn load_manifest_source(
+    name: &Path,
+    file_source: String,
+    options: &LoadOptions,
+) -> Result<(Vec<MirrorFile>, Vec<ValidationError>), Error> {
+    if options.fix {
+        bail!("{} cannot be fixed, as it's not a file", name.display());
+    }
+    load_parsed(
+        vec![(name.to_owned(), parse_source(name, file_source))],
+        options,
+    )
+}
+
+fn parse_file(path: &Path) -> Parsed {
+    let file_source =
+        std::fs::read_to_string(path).map_err(|err| parse_error(path, None, err.into()))?;
+    parse_source(path, file_source)
+}
+
+fn parse_source(path: &Path, file_source: String) -> Parsed {
+    let manifest = toml::from_str::<Manifest>(&file_source).map_err(|err| {
+        if let Some((key, line)) = unknown_field(&file_source) {
+            let message = format!("Unknown field `{key}`");
+            let snippet = file_source.lines().nth(line - 1).unwrap_or_default();
+            return ValidationError {
+                kind: ValidationErrorKind::Parse,
+                rendered: format!(
+                    "{message}:\n\
+                     # {} (line {line})\n{snippet}\n",
+                    path.display()
+                ),
+                message,
+                locations: vec![ErrorLocation {
+                    file: path.to_owned(),
+                    line: Some(line),
+                }],
+            };
+        }
+        let line = err.span().map(|span| line_of(&file_source, span.start));
+        parse_error(path, line, err.into())
+    })?;
+    Ok((file_source, manifest))
+}
+
+/// Find the first key of an entry that isn't a field of its kind of entry, with its line. Entries
+/// with a typo in a field otherwise fail to parse with an error only saying that they don't match
+/// any kind of entry.
+fn unknown_field(file_source: &str) -> Option<(String, usize)> {
+    fn fields<T: JsonSchema>() -> Vec<String> {
+        schemars::schema_for!(T)
+            .schema
+            .object
+            .map(|object| object.properties.into_keys().collect())
+            .unwrap_or_default()
+    }
+
+    let document = toml_edit::ImDocument::parse(file_source).ok()?;
+    let entries = document.as_table().get("files")?.as_array_of_tables()?;
+    for entry in entries.iter() {
+        let known = match entry.contains_key("legacy") {
+            true => fields::<ManifestFileLegacy>(),
+            false => fields::<ManifestFileManaged>(),
+        };
+        for (key, _) in entry.iter() {
+            if known.iter().any(|field| field == key) {
+                continue;
+            }
+            let (key, _) = entry.get_key_value(key)?;
+            let line = line_of(file_source, key.span()?.start);
+            return Some((key.get().to_string(), line));
+        }
+    }
+    None
+}
+
+fn parse_error(path: &Path, line: Option<usize>, err: Error) -> ValidationError {
+    let err = err.context(format!("failed to read {}", path.display()));
+    ValidationError {
+        kind: ValidationErrorKind::Parse,
+        message: format!("{err:#}"),
+        locations: vec![ErrorLocation {
+            file: path.to_owned(),
+            line,
+        }],
+        rendered: format!("{err:?}"),
+    }
+}
+
+fn load_parsed(
+    parsed: Vec<(PathBuf, Parsed)>,
+    options: &LoadOptions,
+) -> Result<(Vec<MirrorFile>, Vec<ValidationError>), Error> {
     let mut result = Vec::new();
     let mut cache = LocationCache::default();
     let mut errors = Vec::new();
 
-    fn emit_error(
-        error: String,
+    fn describe(
+        message: String,
         mirror_file: &MirrorFile,
         file_source: &str,
         cache: &LocationCache,
-        errors: &mut Vec<String>,
-    ) {
+    ) -> ValidationError {
         let location = cache
             .seen_paths
             .get(&mirror_file.name)
+            .or_else(|| cache.skipped_paths.get(&mirror_file.name))
             .unwrap()
             .first()
             .unwrap();
-        let (src_line, snippet) = span_info(&file_source, location);
-        errors.push(format!(
-            "{error}:\n\
-             # {} (line {src_line})\n{snippet}\n",
-            location.file.display()
-        ));
+        let (src_line, snippet) = span_info(file_source, location);
+        ValidationError {
+            kind: ValidationErrorKind::InvalidEntry,
+            rendered: format!(
+                "{message}:\n\
+                 # {} (line {src_line})\n{snippet}\n",
+                location.file.display()
+            ),
+            message,
+            locations: vec![ErrorLocation {
+                file: location.file.clone(),
+                line: Some(src_line),
+            }],
+        }
+    }
+
+    fn emit_error(
+        error: String,
+        mirror_file: &MirrorFile,
+        file_source: &str,
+        cache: &LocationCache,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        errors.push(describe(error, mirror_file, file_source, cache));
     }
 
-    fn load_inner(
-        load_from: &Path,
+    fn load_file(
+        path: &Path,
+        file_source: &str,
+        manifest: Manifest,
+        options: &LoadOptions,
         result: &mut Vec<MirrorFile>,
         cache: &mut LocationCache,
-        errors: &mut Vec<String>,
+        errors: &mut Vec<ValidationError>,
     ) -> anyhow::Result<()> {
-        for entry in load_from.read_dir()? {
-            let path = entry?.path();
-            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("toml") {
-                let file_source = std::fs::read_to_string(&path)
-                    .map_err(Error::from)
-                    .with_context(|| format!("failed to read {}", path.display()))?;
-                let manifest = toml::from_str::<Manifest>(&file_source)
-                    .map_err(Error::from)
-                    .with_context(|| format!("failed to read {}", path.display()))?;
-                record_locations(&path, &manifest, cache);
-
-                for file in manifest.files {
-                    let mirror_file = match file.into_inner() {
-                        ManifestFile::Legacy(legacy) => MirrorFile {
-                            name: legacy.name,
-                            sha256: legacy.sha256,
-                            source: Source::Legacy,
-                            rename_from: None,
-                        },
-                        ManifestFile::Managed(managed) => MirrorFile {
-                            name: managed.name,
-                            sha256: managed.sha256,
-                            source: Source::Url(managed.source),
-                            rename_from: managed.rename_from,
-                        },
+        record_locations(path, &manifest, cache);
+
+        // Fields to fix, as (index in the manifest, field, value to set).
+        let mut fixes = Vec::new();
+        for (index, file) in manifest.files.into_iter().enumerate() {
+            let mut signature = None;
+            let mut allow_extension = true;
+            let mut source = Ok(Source::Legacy);
+            let mut mirror_file = match file.into_inner() {
+                ManifestFile::Legacy(legacy) => MirrorFile {
+                    name: legacy.name,
+                    sha256: legacy.sha256,
+                    sha512: None,
+                    source: Source::Legacy,
+                    tags: BTreeMap::new(),
+                    license: None,
+                    rename_from: None,
+                    decompress: None,
+                    format: None,
+                    signature: None,
+                    enabled: legacy.enabled,
+                },
+                ManifestFile::Managed(managed) => {
+                    // The source and the signature are validated below, once the entry can be
+                    // reported.
+                    signature = managed.signature;
+                    allow_extension = managed.allow_extension;
+                    source = match (managed.source, managed.git) {
+                        (Some(url), None) => url.resolve().map(Source::Url),
+                        (None, Some(git)) => git.repo.resolve().and_then(|repo| {
+                            git_archive_url(&repo, &git.rev).map(|archive| Source::Git {
+                                repo,
+                                rev: git.rev,
+                                archive,
+                            })
+                        }),
+                        _ => Err("Exactly one of `source` and `git` must be present".to_string()),
                     };
-                    if mirror_file.name.starts_with('/') {
+                    MirrorFile {
+                        name: managed.name,
+                        sha256: managed.sha256,
+                        sha512: managed.sha512,
+                        source: Source::Legacy,
+                        tags: managed.tags,
+                        license: Some(managed.license),
+                        rename_from: managed.rename_from,
+                        decompress: managed.decompress,
+                        format: managed.format,
+                        signature: None,
+                        enabled: managed.enabled,
+                    }
+                }
+            };
+            match source {
+                Ok(source) => mirror_file.source = source,
+                Err(error) => {
+                    emit_error(error, &mirror_file, file_source, cache, errors);
+                    continue;
+                }
+            }
+            // Whitespace pasted along with a value is preserved inside the quotes, and would cause
+            // confusing mismatches later on.
+            for (field, value) in [("name", &mirror_file.name), ("sha256", &mirror_file.sha256)] {
+                if value.trim() == value {
+                    continue;
+                }
+                if options.fix {
+                    fixes.push((index, field, value.trim().to_string()));
+                } else {
+                    emit_error(
+                        format!("The `{field}` field has leading or trailing whitespace"),
+                        &mirror_file,
+                        file_source,
+                        cache,
+                        errors,
+                    );
+                }
+            }
+            if let Some(signature) = signature {
+                let url = signature.url.resolve().map_err(|error| {
+                    emit_error(error, &mirror_file, file_source, cache, errors);
+                });
+                let key = match (signature.minisign_key, signature.pgp_key_file) {
+                    (Some(key), None) => Some(SignatureKey::Minisign(key)),
+                    // Key files are relative to the manifest.
+                    (None, Some(key_file)) => Some(SignatureKey::Pgp(
+                        path.parent().unwrap_or(Path::new("")).join(key_file),
+                    )),
+                    _ => {
                         emit_error(
-                            "Mirrored path cannot start with a slash (/)".to_string(),
+                            "The `signature` section must contain exactly one of `minisign-key` \
+                             and `pgp-key-file`"
+                                .to_string(),
                             &mirror_file,
-                            &file_source,
+                            file_source,
                             cache,
                             errors,
                         );
+                        None
                     }
+                };
+                if let (Ok(url), Some(key)) = (url, key) {
+                    mirror_file.signature = Some(Signature { url, key });
+                }
+            }
+            if let Some(error) = tags_error(&mirror_file.tags) {
+                emit_error(error, &mirror_file, file_source, cache, errors);
+            }
+            if let Some(sha512) = &mirror_file.sha512 {
+                let error = if !is_sha512_hex(sha512) {
+                    Some("The `sha512` field must be a lowercase hex-encoded SHA-512 hash")
+                } else if mirror_file.decompress.is_some() {
+                    Some("The `sha512` field cannot be combined with `decompress`")
+                } else {
+                    None
+                };
+                if let Some(error) = error {
+                    emit_error(error.to_string(), &mirror_file, file_source, cache, errors);
+                }
+            }
+            if options.require_license
+                && mirror_file
+                    .license
+                    .as_deref()
+                    .is_some_and(|license| license.trim().is_empty())
+            {
+                emit_error(
+                    "The `license` field cannot be empty".to_string(),
+                    &mirror_file,
+                    file_source,
+                    cache,
+                    errors,
+                );
+            }
+            let insecure = match &mirror_file.source {
+                Source::Url(url) if url.scheme() == "http" => {
+                    Some("The `source` is downloaded over HTTP instead of HTTPS")
+                }
+                Source::Git { repo, .. } if repo.scheme() == "http" => {
+                    Some("The `git` repository is served over HTTP instead of HTTPS")
+                }
+                _ => None,
+            };
+            if let Some(message) = insecure {
+                if options.deny_insecure_urls {
+                    emit_error(
+                        message.to_string(),
+                        &mirror_file,
+                        file_source,
+                        cache,
+                        errors,
+                    );
+                } else {
+                    let warning = describe(message.to_string(), &mirror_file, file_source, cache);
+                    warn!("{warning}");
+                }
+            }
+            // Legacy entries were mirrored before the extensions were restricted.
+            if !allow_extension && let Some(error) = options.extension_error(&mirror_file.name) {
+                emit_error(error, &mirror_file, file_source, cache, errors);
+            }
+            if mirror_file.name.starts_with('/') {
+                emit_error(
+                    "Mirrored path cannot start with a slash (/)".to_string(),
+                    &mirror_file,
+                    file_source,
+                    cache,
+                    errors,
+                );
+            }
 
-                    if let Source::Url(ref source) = mirror_file.source {
-                        if let Some(file_name) = source.path().split('/').last()
-                            && let Some(path_name) = mirror_file.name.split('/').last()
-                        {
-                            match mirror_file.rename_from {
-                                Some(ref rename_from) => {
-                                    if path_name == file_name {
-                                        emit_error(
-                                            format!(
-                                                "`rename-from` field isn't needed since `source` and `name` field have the same file name (`{file_name}`)"
-                                            ),
-                                            &mirror_file,
-                                            &file_source,
-                                            cache,
-                                            errors,
-                                        );
-                                    } else if rename_from != file_name {
-                                        emit_error(
-                                            format!(
-                                                "`rename-from` field value doesn't match name from the URL `{source}` (`{file_name}` != `{rename_from}`)"
-                                            ),
-                                            &mirror_file,
-                                            &file_source,
-                                            cache,
-                                            errors,
-                                        );
-                                    }
-                                }
-                                None => {
-                                    if path_name != file_name {
-                                        emit_error(
-                                            format!(
-                                                "The name from the URL `{source}` doesn't match the `name` field (`{file_name}` != `{path_name}`). \
-                                             Add `rename-from = {file_name:?}` to fix this error"
-                                            ),
-                                            &mirror_file,
-                                            &file_source,
-                                            cache,
-                                            errors,
-                                        );
-                                    }
-                                }
-                            }
+            if let Source::Url(ref source) = mirror_file.source
+                && let Some(file_name) = source.path().split('/').next_back()
+                && let Some(path_name) = mirror_file.name.trim().split('/').next_back()
+            {
+                match mirror_file.rename_from {
+                    Some(ref rename_from) => {
+                        if path_name == file_name {
+                            emit_error(
+                                format!(
+                                    "`rename-from` field isn't needed since `source` and `name` field have the same file name (`{file_name}`)"
+                                ),
+                                &mirror_file,
+                                file_source,
+                                cache,
+                                errors,
+                            );
+                        } else if rename_from != file_name {
+                            emit_error(
+                                format!(
+                                    "`rename-from` field value doesn't match name from the URL `{source}` (`{file_name}` != `{rename_from}`)"
+                                ),
+                                &mirror_file,
+                                file_source,
+                                cache,
+                                errors,
+                            );
+                        }
+                    }
+                    None => {
+                        if path_name != file_name && options.fix {
+                            fixes.push((index, "rename-from", file_name.to_string()));
+                            mirror_file.rename_from = Some(file_name.to_string());
+                        } else if path_name != file_name {
+                            emit_error(
+                                format!(
+                                    "The name from the URL `{source}` doesn't match the `name` field (`{file_name}` != `{path_name}`). \
+                                 Add `rename-from = {file_name:?}` to fix this error"
+                                ),
+                                &mirror_file,
+                                file_source,
+                                cache,
+                                errors,
+                            );
                         }
                     }
-                    result.push(mirror_file);
                 }
-            } else if path.is_dir() {
-                load_inner(&path, result, cache, errors)?;
             }
+            // Errors are reported above with the original name, as it's the one the locations
+            // of the entries are recorded with.
+            if options.fix {
+                mirror_file.name = mirror_file.name.trim().to_string();
+                mirror_file.sha256 = mirror_file.sha256.trim().to_string();
+            }
+            result.push(mirror_file);
+        }
+
+        if !fixes.is_empty() {
+            apply_fixes(path, file_source, &fixes)?;
         }
         Ok(())
     }
 
-    load_inner(load_from, &mut result, &mut cache, &mut errors)?;
-    find_errors(cache, &mut errors);
+    let mut manifests = Vec::new();
+    for (path, parsed) in parsed {
+        match parsed {
+            Ok((file_source, manifest)) => manifests.push((path, file_source, manifest)),
+            Err(err) => errors.push(err),
+        }
+    }
+    // The content of the manifests is kept to show the duplicated entries, as manifests read
+    // from stdin can't be read again.
+    let mut sources = HashMap::new();
+    for (path, file_source, manifest) in manifests {
+        load_file(
+            &path,
+            &file_source,
+            manifest,
+            options,
+            &mut result,
+            &mut cache,
+            &mut errors,
+        )?;
+        sources.insert(path, file_source);
+    }
+    find_errors(cache, sources, options, &mut errors);
+    // The duplicates are found by iterating over hash maps: sort the errors so that the output is
+    // the same on every run.
+    errors.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
     Ok((result, errors))
 }
 
+/// JSON Schema of the manifests, for editors to validate and complete them.
+pub(crate) fn schema() -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(&schemars::schema_for!(
+        Manifest
+    ))?)
+}
+
+/// Set the fields of the entries at the provided indexes, preserving the rest of the formatting of
+/// the manifest.
+fn apply_fixes(
+    path: &Path,
+    file_source: &str,
+    fixes: &[(usize, &str, String)],
+) -> Result<(), Error> {
+    let mut document = file_source
+        .parse::<toml_edit::DocumentMut>()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let files = document["files"]
+        .as_array_of_tables_mut()
+        .with_context(|| format!("`files` is not an array of tables in {}", path.display()))?;
+
+    for (index, field, value) in fixes {
+        let table = files.get_mut(*index).unwrap();
+        info!(
+            "fixed: set `{field} = {value:?}` on {} in {}",
+            table["name"].as_str().unwrap_or("<unknown>").trim(),
+            path.display()
+        );
+        table.insert(field, toml_edit::value(value));
+    }
+
+    std::fs::write(path, document.to_string())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Change the `name` of the entry called `old` to `new`, in whichever manifest contains it. The
+/// `rename-from` field is updated to match the new name, preserving the rest of the formatting of
+/// the manifest. Returns the path of the updated manifest.
+pub(crate) fn rename_entry(load_from: &Path, old: &str, new: &str) -> Result<PathBuf, Error> {
+    for path in manifest_paths(load_from)? {
+        let file_source = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut document = file_source
+            .parse::<toml_edit::DocumentMut>()
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        let files = document["files"]
+            .as_array_of_tables_mut()
+            .with_context(|| format!("`files` is not an array of tables in {}", path.display()))?;
+
+        let Some(table) = files
+            .iter_mut()
+            .find(|table| table.get("name").and_then(|name| name.as_str()) == Some(old))
+        else {
+            continue;
+        };
+        table.insert("name", toml_edit::value(new));
+        if let Some(source) = table.get("source").and_then(|source| source.as_str())
+            && let Ok(source) = Url::parse(source)
+            && let Some(file_name) = source.path().split('/').next_back()
+            && let Some(path_name) = new.split('/').next_back()
+        {
+            if path_name == file_name {
+                table.remove("rename-from");
+            } else {
+                table.insert("rename-from", toml_edit::value(file_name));
+            }
+        }
+
+        std::fs::write(&path, document.to_string())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        return Ok(path);
+    }
+    bail!("no manifest contains an entry named {old}");
+}
+
+/// Check that the manifests are formatted canonically: entries sorted by name, with their fields
+/// in a consistent order. Comments before an entry are kept with it, except the ones before the
+/// first entry which stay at the top of the manifest. Returns the manifests that are not
+/// formatted canonically, after rewriting them if `write` is set.
+pub(crate) fn check_format(load_from: &Path, write: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut unformatted = Vec::new();
+    for path in manifest_paths(load_from)? {
+        let file_source = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let formatted = canonical_format(&file_source)
+            .with_context(|| format!("failed to format {}", path.display()))?;
+        if formatted == file_source {
+            continue;
+        }
+        if write {
+            std::fs::write(&path, &formatted)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+        unformatted.push(path);
+    }
+    Ok(unformatted)
+}
+
+fn canonical_format(file_source: &str) -> Result<String, Error> {
+    #[derive(Serialize)]
+    struct SingleEntry<'a> {
+        files: [&'a ManifestFile; 1],
+    }
+
+    let manifest = toml::from_str::<Manifest>(file_source)?;
+    let document = file_source.parse::<toml_edit::DocumentMut>()?;
+    let tables = document["files"]
+        .as_array_of_tables()
+        .context("`files` is not an array of tables")?;
+
+    let mut output = String::new();
+    let mut entries = Vec::new();
+    for (index, (file, table)) in manifest.files.into_iter().zip(tables.iter()).enumerate() {
+        let prefix = comments(table.decor().prefix());
+        if index == 0 {
+            output.push_str(&prefix);
+            entries.push((file.into_inner(), String::new(), table));
+        } else {
+            entries.push((file.into_inner(), prefix, table));
+        }
+    }
+    entries.sort_by(|(a, _, _), (b, _, _)| a.name().cmp(b.name()));
+
+    for (index, (file, prefix, table)) in entries.iter().enumerate() {
+        if index != 0 {
+            output.push('\n');
+        }
+        output.push_str(prefix);
+        // Serializing the entry as part of `files` renders nested tables with the right header.
+        let mut entry =
+            toml::to_string(&SingleEntry { files: [file] })?.parse::<toml_edit::DocumentMut>()?;
+        let formatted = entry["files"]
+            .as_array_of_tables_mut()
+            .and_then(|tables| tables.get_mut(0))
+            .context("the entry was not serialized as a table")?;
+        // The comments inside the entry are moved along with the keys they're attached to.
+        copy_suffix_comment(table.decor(), formatted.decor_mut());
+        copy_comments(table, formatted);
+        let expected = comment_count(table.decor().suffix()) + table_comment_count(table);
+        if comment_count(formatted.decor().suffix()) + table_comment_count(formatted) != expected {
+            bail!(
+                "the comments inside the entry of {} can't be preserved when formatting, move \
+                 them above its `[[files]]` header",
+                file.name()
+            );
+        }
+        output.push_str(&entry.to_string());
+    }
+    let trailing = comments(Some(document.trailing()));
+    if !trailing.is_empty() {
+        output.push('\n');
+        output.push_str(&trailing);
+    }
+    Ok(output)
+}
+
+/// The comment lines of the raw string, without the surrounding whitespace.
+fn comments(raw: Option<&toml_edit::RawString>) -> String {
+    raw.and_then(|raw| raw.as_str())
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('#'))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+fn comment_count(raw: Option<&toml_edit::RawString>) -> usize {
+    comments(raw).lines().count()
+}
+
+/// Copy the comment at the end of the line from the `from` decor to the `to` decor.
+fn copy_suffix_comment(from: &toml_edit::Decor, to: &mut toml_edit::Decor) {
+    let comment = comments(from.suffix());
+    if !comment.is_empty() {
+        to.set_suffix(format!(" {}", comment.trim_end()));
+    }
+}
+
+/// Copy the comments of the keys and values of `from`, and of its nested tables, to the same keys
+/// of `to`. Comments inside arrays are not copied.
+fn copy_comments(from: &toml_edit::Table, to: &mut toml_edit::Table) {
+    for (mut key, item) in to.iter_mut() {
+        let Some((from_key, from_item)) = from.get_key_value(key.get()) else {
+            continue;
+        };
+        let prefix = comments(from_key.leaf_decor().prefix());
+        if !prefix.is_empty() {
+            key.leaf_decor_mut().set_prefix(prefix);
+        }
+        match (from_item, item) {
+            (toml_edit::Item::Value(from), toml_edit::Item::Value(to)) => {
+                copy_suffix_comment(from.decor(), to.decor_mut());
+            }
+            (toml_edit::Item::Table(from), toml_edit::Item::Table(to)) => {
+                let prefix = comments(from.decor().prefix());
+                if !prefix.is_empty() {
+                    to.decor_mut().set_prefix(prefix);
+                }
+                copy_suffix_comment(from.decor(), to.decor_mut());
+                copy_comments(from, to);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Number of comment lines between the keys of the table or inside its values, including in its
+/// nested tables.
+fn table_comment_count(table: &toml_edit::Table) -> usize {
+    table
+        .iter()
+        .map(|(name, item)| {
+            let key = table.key(name).expect("the key was just iterated");
+            comment_count(key.leaf_decor().prefix())
+                + comment_count(key.leaf_decor().suffix())
+                + match item {
+                    toml_edit::Item::None => 0,
+                    toml_edit::Item::Value(value) => value_comment_count(value),
+                    toml_edit::Item::Table(table) => {
+                        comment_count(table.decor().prefix())
+                            + comment_count(table.decor().suffix())
+                            + table_comment_count(table)
+                    }
+                    toml_edit::Item::ArrayOfTables(tables) => tables
+                        .iter()
+                        .map(|table| {
+                            comment_count(table.decor().prefix())
+                                + comment_count(table.decor().suffix())
+                                + table_comment_count(table)
+                        })
+                        .sum(),
+                }
+        })
+        .sum()
+}
+
+fn value_comment_count(value: &toml_edit::Value) -> usize {
+    comment_count(value.decor().prefix())
+        + comment_count(value.decor().suffix())
+        + match value {
+            toml_edit::Value::Array(array) => {
+                comment_count(Some(array.trailing()))
+                    + array.iter().map(value_comment_count).sum::<usize>()
+            }
+            _ => 0,
+        }
+}
+
+/// Paths of either a single manifest, or all the manifests in a directory (recursively).
+fn manifest_paths(load_from: &Path) -> Result<Vec<PathBuf>, Error> {
+    if load_from.is_file() {
+        return Ok(vec![load_from.to_owned()]);
+    }
+    let mut paths = Vec::new();
+    for entry in load_from.read_dir()? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("toml") {
+            paths.push(path);
+        } else if path.is_dir() {
+            paths.extend(manifest_paths(&path)?);
+        }
+    }
+    Ok(paths)
+}
+
+/// Look for entries in the manifest at `toml_path` sharing the CDN path, URL or hash with
+/// `entry`. Returns a description of the conflicting entries, if any.
+pub(crate) fn find_duplicates(
+    toml_path: &Path,
+    entry: &ManifestFileManaged,
+) -> Result<Option<String>, Error> {
+    if !toml_path.is_file() {
+        return Ok(None);
+    }
+    let file_source = std::fs::read_to_string(toml_path)
+        .with_context(|| format!("failed to read {}", toml_path.display()))?;
+    let manifest = toml::from_str::<Manifest>(&file_source)
+        .with_context(|| format!("failed to read {}", toml_path.display()))?;
+
+    let mut cache = LocationCache::default();
+    record_locations(toml_path, &manifest, &mut cache);
+
+    let mut duplicates = BTreeSet::new();
+    for locations in [
+        cache.seen_paths.get(&entry.name),
+        entry
+            .source
+            .as_ref()
+            .and_then(|url| url.resolve().ok())
+            .and_then(|url| cache.seen_urls.get(&normalize_url(&url))),
+        cache.seen_hashes.get(&entry.sha256),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        duplicates.extend(locations.iter().cloned());
+    }
+
+    if duplicates.is_empty() {
+        Ok(None)
+    } else {
+        let mut file_cache = HashMap::from([(toml_path.to_owned(), file_source)]);
+        Ok(Some(format_locations(&mut file_cache, &duplicates)))
+    }
+}
+
+/// Normalize `url` to detect the duplicates that only differ trivially, without changing which
+/// resource it points to: the fragment (which is not sent to the server) and the trailing slash of
+/// the path are removed, and percent-encoded unreserved characters are decoded. Parsing the URL
+/// already lowercased its host and removed its default port. The query is kept as is, as the order
+/// of its parameters can matter to the server.
+fn normalize_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    let mut path = String::with_capacity(url.path().len());
+    let mut rest = url.path();
+    while let Some(start) = rest.find('%') {
+        path.push_str(&rest[..start]);
+        let escape = rest
+            .get(start + 1..start + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()));
+        match escape.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                path.push(byte as char);
+                rest = &rest[start + 3..];
+            }
+            Some(_) => {
+                path.push('%');
+                path.push_str(&escape.unwrap().to_ascii_uppercase());
+                rest = &rest[start + 3..];
+            }
+            None => {
+                path.push('%');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    path.push_str(rest);
+    if path.len() > 1
+        && let Some(trimmed) = path.strip_suffix('/')
+    {
+        path = trimmed.to_string();
+    }
+    url.set_path(&path);
+    url
+}
+
 fn record_locations(toml_path: &Path, manifest: &Manifest, cache: &mut LocationCache) {
     for file in &manifest.files {
         let span = Span(file.span());
@@ -177,15 +915,34 @@ fn record_locations(toml_path: &Path, manifest: &Manifest, cache: &mut LocationC
             file: toml_path.to_owned(),
             span,
         };
-        let (hash, name, url) = match file {
-            ManifestFile::Legacy(f) => {
-                if f.skip_validation {
-                    return;
-                }
+        let skip_validation = match file {
+            ManifestFile::Legacy(f) => f.skip_validation,
+            ManifestFile::Managed(f) => f.skip_validation,
+        };
+        if skip_validation {
+            cache
+                .skipped_paths
+                .entry(file.name().to_string())
+                .or_default()
+                .insert(location);
+            continue;
+        }
 
-                (f.sha256.clone(), f.name.clone(), None)
+        let (hash, name, url) = match file {
+            ManifestFile::Legacy(f) => (f.sha256.clone(), f.name.clone(), None),
+            ManifestFile::Managed(f) => {
+                // Entries referencing unset variables are reported when loading them.
+                let url = match (&f.source, &f.git) {
+                    (Some(url), _) => url.resolve().ok(),
+                    (None, Some(git)) => git
+                        .repo
+                        .resolve()
+                        .ok()
+                        .and_then(|repo| git_archive_url(&repo, &git.rev).ok()),
+                    (None, None) => None,
+                };
+                (f.sha256.clone(), f.name.clone(), url)
             }
-            ManifestFile::Managed(f) => (f.sha256.clone(), f.name.clone(), Some(f.source.clone())),
         };
         cache
             .seen_hashes
@@ -200,7 +957,7 @@ fn record_locations(toml_path: &Path, manifest: &Manifest, cache: &mut LocationC
         if let Some(url) = url {
             cache
                 .seen_urls
-                .entry(url)
+                .entry(normalize_url(&url))
                 .or_default()
                 .insert(location.clone());
         }
@@ -208,125 +965,468 @@ fn record_locations(toml_path: &Path, manifest: &Manifest, cache: &mut LocationC
 }
 
 fn span_info<'a>(content: &'a str, location: &Location) -> (usize, &'a str) {
-    // Find the corresponding line number
+    let src_line = line_of(content, location.span.0.start);
+    let snippet = &content[location.span.0.start..location.span.0.end];
+    (src_line, snippet)
+}
+
+/// Line number (starting from 1) of the byte at `offset` in `content`.
+fn line_of(content: &str, offset: usize) -> usize {
     let mut accumulated_chars = 0;
-    let mut src_line = 0;
     for (index, line) in content.lines().enumerate() {
         accumulated_chars += line.len() + 1; // +1 for newline
-        if accumulated_chars > location.span.0.start {
-            src_line = index + 1;
-            break;
+        if accumulated_chars > offset {
+            return index + 1;
         }
     }
+    0
+}
 
-    let snippet = &content[location.span.0.start..location.span.0.end];
-    (src_line, snippet)
+fn read_cached<'a>(cache: &'a mut HashMap<PathBuf, String>, file: &Path) -> &'a str {
+    cache.entry(file.to_owned()).or_insert_with(|| {
+        std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("cannot load file from {}: {e:?}", file.display()))
+    })
 }
 
-fn find_errors(cache: LocationCache, errors: &mut Vec<String>) {
-    let mut file_cache: HashMap<PathBuf, String> = HashMap::new();
+fn format_locations(
+    cache: &mut HashMap<PathBuf, String>,
+    locations: &BTreeSet<Location>,
+) -> String {
+    use std::fmt::Write;
 
-    fn format_locations(
-        cache: &mut HashMap<PathBuf, String>,
-        locations: &BTreeSet<Location>,
-    ) -> String {
-        use std::fmt::Write;
+    let mut output = String::new();
+    for location in locations {
+        let content = read_cached(cache, &location.file);
+        let (src_line, snippet) = span_info(content, location);
+        writeln!(
+            output,
+            "# {} (line {src_line})\n{snippet}\n",
+            location.file.display()
+        )
+        .unwrap();
+    }
 
-        let mut output = String::new();
-        for location in locations {
-            let content = cache.entry(location.file.clone()).or_insert_with(|| {
-                std::fs::read_to_string(&location.file).unwrap_or_else(|e| {
-                    panic!("cannot load file from {}: {e:?}", location.file.display())
-                })
-            });
+    output
+}
 
-            let (src_line, snippet) = span_info(&content, location);
-            writeln!(
-                output,
-                "# {} (line {src_line})\n{snippet}\n",
-                location.file.display()
-            )
-            .unwrap();
+fn find_errors(
+    cache: LocationCache,
+    mut file_cache: HashMap<PathBuf, String>,
+    options: &LoadOptions,
+    errors: &mut Vec<ValidationError>,
+) {
+    let mut duplicates = |kind, message: String, locations: BTreeSet<Location>| {
+        if locations.len() <= 1 {
+            return;
         }
+        let rendered = format!(
+            "{message}:\n{}",
+            format_locations(&mut file_cache, &locations)
+        );
+        errors.push(ValidationError {
+            kind,
+            message,
+            locations: locations
+                .iter()
+                .map(|location| ErrorLocation {
+                    file: location.file.clone(),
+                    line: Some(span_info(read_cached(&mut file_cache, &location.file), location).0),
+                })
+                .collect(),
+            rendered,
+        });
+    };
 
-        output
+    // Paths differing only by a trailing slash (or by case, when the CDN ignores it) are distinct
+    // objects, but are confusingly served at overlapping URLs.
+    let mut similar_paths = HashMap::<String, Vec<&String>>::new();
+    for path in cache.seen_paths.keys() {
+        let mut normalized = path.trim_end_matches('/').to_string();
+        if options.case_insensitive_paths {
+            normalized = normalized.to_lowercase();
+        }
+        similar_paths.entry(normalized).or_default().push(path);
+    }
+    for mut paths in similar_paths.into_values() {
+        if paths.len() <= 1 {
+            continue;
+        }
+        paths.sort();
+        let locations = paths
+            .iter()
+            .flat_map(|path| &cache.seen_paths[*path])
+            .cloned()
+            .collect();
+        let difference = match options.case_insensitive_paths {
+            true => "a trailing slash or by case",
+            false => "a trailing slash",
+        };
+        let paths = paths
+            .iter()
+            .map(|path| format!("`{path}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        duplicates(
+            ValidationErrorKind::ConflictingPath,
+            format!("The CDN paths {paths} only differ by {difference}"),
+            locations,
+        );
     }
 
     for (path, locations) in cache.seen_paths {
-        if locations.len() > 1 {
-            errors.push(format!(
-                "The following entries share the same CDN path `{path}`:\n{}",
-                format_locations(&mut file_cache, &locations)
-            ));
-        }
+        duplicates(
+            ValidationErrorKind::DuplicatePath,
+            format!("The following entries share the same CDN path `{path}`"),
+            locations,
+        );
     }
     for (url, locations) in cache.seen_urls {
-        if locations.len() > 1 {
-            errors.push(format!(
-                "The following entries share the same URL `{url}`:\n{}",
-                format_locations(&mut file_cache, &locations)
-            ));
-        }
+        duplicates(
+            ValidationErrorKind::DuplicateUrl,
+            format!("The following entries share the same URL `{url}`"),
+            locations,
+        );
     }
     for (hash, locations) in cache.seen_hashes {
-        if locations.len() > 1 {
-            errors.push(format!(
-                "The following entries share the same hash `{hash}`:\n{}",
-                format_locations(&mut file_cache, &locations)
-            ));
-        }
+        duplicates(
+            ValidationErrorKind::DuplicateHash,
+            format!("The following entries share the same hash `{hash}`"),
+            locations,
+        );
+    }
+}
+
+/// Error found while loading the manifests. It's displayed as a human-readable message pointing
+/// to the entries involved, and can be serialized for tools annotating the manifests.
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    pub message: String,
+    /// Entries the error refers to, or the manifest that couldn't be parsed.
+    pub locations: Vec<ErrorLocation>,
+    #[serde(skip)]
+    rendered: String,
+}
+
+impl ValidationError {
+    /// Orders the errors by the first entry they refer to, then by kind.
+    fn sort_key(&self) -> (Option<(&Path, Option<usize>)>, ValidationErrorKind) {
+        let location = self
+            .locations
+            .first()
+            .map(|location| (location.file.as_path(), location.line));
+        (location, self.kind)
     }
 }
 
-pub(crate) struct MirrorFile {
-    pub(crate) name: String,
-    pub(crate) sha256: String,
-    pub(crate) source: Source,
-    pub(crate) rename_from: Option<String>,
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.rendered)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValidationErrorKind {
+    Parse,
+    InvalidEntry,
+    DuplicatePath,
+    ConflictingPath,
+    DuplicateUrl,
+    DuplicateHash,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorLocation {
+    pub file: PathBuf,
+    /// Starting from 1. Only missing when the manifest couldn't be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+/// A file mirrored on the CDN, as defined in the manifests.
+pub struct MirrorFile {
+    /// Path of the file on the CDN.
+    pub name: String,
+    pub sha256: String,
+    /// Secondary hash of the downloaded file, verified along with `sha256`.
+    pub sha512: Option<String>,
+    pub source: Source,
+    /// S3 tags of the uploaded object, on top of the ones derived from the entry.
+    pub tags: BTreeMap<String, String>,
+    /// Only present for managed files.
+    pub license: Option<String>,
+    pub rename_from: Option<String>,
+    pub decompress: Option<Compression>,
+    pub format: Option<ArchiveFormat>,
+    pub signature: Option<Signature>,
+    /// Disabled files are validated, but not checked nor uploaded.
+    pub enabled: bool,
+}
+
+/// Detached signature the downloaded file is verified against.
+#[derive(Clone)]
+pub struct Signature {
+    pub url: Url,
+    pub key: SignatureKey,
+}
+
+#[derive(Clone)]
+pub enum SignatureKey {
+    /// Minisign public key, encoded in base64.
+    Minisign(String),
+    /// Path of an OpenPGP public key, either ASCII-armored or binary.
+    Pgp(PathBuf),
 }
 
-pub(crate) enum Source {
+/// Where a file is downloaded from. New kinds of sources may be added, so matches on it must
+/// have a wildcard arm.
+#[non_exhaustive]
+pub enum Source {
     Url(Url),
+    /// Snapshot of the source tree of a git repository at `rev`, downloaded from the `archive`
+    /// endpoint of its forge.
+    Git {
+        repo: Url,
+        rev: String,
+        archive: Url,
+    },
     Legacy,
 }
 
-#[derive(Debug, Deserialize)]
+impl Source {
+    /// URL the file is downloaded from, or `None` for legacy files.
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            Source::Url(url) | Source::Git { archive: url, .. } => Some(url),
+            Source::Legacy => None,
+        }
+    }
+}
+
+/// URL of the tarball of `repo` at `rev`, as generated by GitHub or GitLab. Other forges are not
+/// supported, as there is no common way to download an archive of a repository.
+fn git_archive_url(repo: &Url, rev: &str) -> Result<Url, String> {
+    if rev.is_empty() || rev.contains('/') {
+        return Err(format!("Invalid git revision `{rev}`"));
+    }
+    let path = repo.path().trim_end_matches('/').trim_end_matches(".git");
+    let url = match repo.host_str() {
+        Some("github.com") => format!("https://github.com{path}/archive/{rev}.tar.gz"),
+        Some(host) if host == "gitlab.com" || host.starts_with("gitlab.") => {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            format!("https://{host}{path}/-/archive/{rev}/{name}-{rev}.tar.gz")
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported git repository `{repo}`, only GitHub and GitLab repositories can be \
+                 mirrored"
+            ));
+        }
+    };
+    Url::parse(&url).map_err(|e| format!("Invalid git repository `{repo}`: {e}"))
+}
+
+/// Compression format of a mirrored file whose hash refers to the decompressed content.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Archive format a mirrored file is expected to be in.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.xz")]
+    TarXz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarXz => "tar.xz",
+            ArchiveFormat::Zip => "zip",
+        })
+    }
+}
+
+/// A manifest listing files to mirror.
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Manifest {
+    #[schemars(with = "Vec<ManifestFile>")]
     files: Vec<toml::Spanned<ManifestFile>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 enum ManifestFile {
     Legacy(ManifestFileLegacy),
-    Managed(ManifestFileManaged),
+    Managed(Box<ManifestFileManaged>),
 }
 
-#[derive(Debug, Deserialize)]
+impl ManifestFile {
+    fn name(&self) -> &str {
+        match self {
+            ManifestFile::Legacy(legacy) => &legacy.name,
+            ManifestFile::Managed(managed) => &managed.name,
+        }
+    }
+}
+
+/// A file uploaded before this tool existed, which is never downloaded again.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct ManifestFileLegacy {
+    /// Path of the file on the CDN.
     name: String,
+    /// SHA-256 of the file.
     sha256: String,
-    #[serde(deserialize_with = "deserialize_true")]
-    #[expect(unused)]
+    /// Must be `true`.
+    #[serde(
+        deserialize_with = "deserialize_true",
+        serialize_with = "serialize_true"
+    )]
+    #[schemars(with = "bool")]
     legacy: (),
-    #[serde(default, rename = "skip-validation")]
+    /// Exclude the entry from the detection of duplicated paths and hashes.
+    #[serde(
+        default,
+        rename = "skip-validation",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
     skip_validation: bool,
+    /// Set to `false` to skip the file when mirroring.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    enabled: bool,
+}
+
+/// Tags always set on the uploaded objects, which can't be set by the entries.
+const DERIVED_TAGS: [&str; 2] = ["managed", "tool"];
+
+/// Check that the tags of an entry can be set on an S3 object, leaving room for the derived ones.
+fn tags_error(tags: &BTreeMap<String, String>) -> Option<String> {
+    // S3 objects can have at most 10 tags.
+    let max = 10 - DERIVED_TAGS.len();
+    if tags.len() > max {
+        return Some(format!("At most {max} `tags` can be set"));
+    }
+    for (key, value) in tags {
+        if DERIVED_TAGS.contains(&key.as_str()) {
+            return Some(format!("The `{key}` tag is set automatically"));
+        } else if key.is_empty() || key.chars().count() > 128 {
+            return Some(format!("The tag `{key}` must be 1 to 128 characters long"));
+        } else if value.chars().count() > 256 {
+            return Some(format!(
+                "The value of the tag `{key}` must be at most 256 characters long"
+            ));
+        } else if key.starts_with("aws:") {
+            return Some(format!("The `aws:` prefix of the tag `{key}` is reserved"));
+        } else if let Some(c) = key.chars().chain(value.chars()).find(|&c| !is_tag_char(c)) {
+            return Some(format!(
+                "The tag `{key}` contains the character {c:?}, only letters, numbers, spaces \
+                 and `+-=._:/@` are allowed"
+            ));
+        }
+    }
+    None
+}
+
+/// Whether S3 allows the character in the keys and values of the tags.
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, ' ' | '+' | '-' | '=' | '.' | '_' | ':' | '/' | '@')
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A file downloaded from its upstream and mirrored by this tool.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ManifestFileManaged {
+    /// Path of the file on the CDN.
     name: String,
+    /// SHA-256 of the file.
     sha256: String,
-    #[serde(deserialize_with = "deserialize_url", serialize_with = "serialize_url")]
-    source: Url,
-    // This field is not considered at all by the automation, we just enforce its presence so that
-    // people adding new entries think about the licensing implications.
+    /// SHA-512 of the file, verified in addition to `sha256`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha512: Option<String>,
+    /// URL of the file to mirror.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    source: Option<ManifestUrl>,
+    /// Mirrors a snapshot of a git repository instead of a `source` URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git: Option<ManifestGit>,
+    /// Licensing of the file. This field is not considered at all by the automation, we just
+    /// enforce its presence so that people adding new entries think about the licensing
+    /// implications.
     license: String,
+    /// Name of the upstream file, when it differs from the last segment of `name`.
     #[serde(default, rename = "rename-from")]
     rename_from: Option<String>,
+    /// When present, `sha256` is the hash of the decompressed file, while the file is mirrored
+    /// without decompressing it.
+    #[serde(default)]
+    decompress: Option<Compression>,
+    /// When present, the downloaded file is checked to be a valid archive of this format.
+    #[serde(default)]
+    format: Option<ArchiveFormat>,
+    /// When present, the downloaded file is verified against an upstream detached signature.
+    #[serde(default)]
+    signature: Option<ManifestSignature>,
+    /// Disabled entries are still validated, but skipped when mirroring, for example while their
+    /// upstream is unavailable.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    enabled: bool,
+    /// Exclude the entry from the detection of duplicated paths, URLs and hashes, for accepted
+    /// duplicates. The entry is still mirrored.
+    #[serde(
+        default,
+        rename = "skip-validation",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    skip_validation: bool,
+    /// Exempts the entry from the `--denied-extension` and `--allowed-extension` policies, once
+    /// mirroring it was agreed on.
+    #[serde(
+        default,
+        rename = "allow-extension",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    allow_extension: bool,
+    /// S3 tags of the uploaded object, used by the lifecycle rules and the cost reports.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
+}
+
+/// Upstream detached signature of a file.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ManifestSignature {
+    /// URL of the signature.
+    #[schemars(with = "String")]
+    url: ManifestUrl,
+    /// Minisign public key, exclusive with `pgp-key-file`.
+    minisign_key: Option<String>,
+    /// OpenPGP key, relative to the manifest containing the entry. Exclusive with `minisign-key`.
+    pgp_key_file: Option<PathBuf>,
+}
+
+/// Snapshot of a git repository hosted on GitHub or GitLab.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ManifestGit {
+    /// URL of the repository.
+    #[schemars(with = "String")]
+    repo: ManifestUrl,
+    /// Revision to mirror.
+    rev: String,
 }
 
 impl ManifestFileManaged {
@@ -336,24 +1436,99 @@ impl ManifestFileManaged {
         source: Url,
         license: String,
         rename_from: Option<String>,
+        decompress: Option<Compression>,
     ) -> Self {
         Self {
             name,
             sha256,
-            source,
+            sha512: None,
+            source: Some(source.into()),
+            git: None,
             license,
             rename_from,
+            decompress,
+            format: None,
+            signature: None,
+            enabled: true,
+            skip_validation: false,
+            allow_extension: false,
+            tags: BTreeMap::new(),
         }
     }
 }
 
-fn deserialize_url<'de, D: Deserializer<'de>>(de: D) -> Result<Url, D::Error> {
-    let raw = String::deserialize(de)?;
-    Url::parse(&raw).map_err(|e| D::Error::custom(format!("{e:?}")))
+/// Only the environment variables with this prefix can be referenced in the manifests, so that a
+/// manifest can't leak the credentials present in the environment of the uploads.
+const ENV_PREFIX: &str = "CI_MIRRORS_";
+
+/// URL in a manifest, kept as written so that formatting the manifests preserves its references
+/// to environment variables. It's resolved with [`ManifestUrl::resolve`] when loading the entries.
+#[derive(Debug)]
+pub struct ManifestUrl(String);
+
+impl ManifestUrl {
+    /// Replace the `${VAR}` references with the value of the environment variables, and parse
+    /// the resulting URL.
+    fn resolve(&self) -> Result<Url, String> {
+        let raw = &self.0;
+        let mut result = String::with_capacity(raw.len());
+        let mut rest = raw.as_str();
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            let Some(len) = rest[start + 2..].find('}') else {
+                return Err(format!("Unterminated variable reference in {raw}"));
+            };
+            let var = &rest[start + 2..start + 2 + len];
+            if !var.starts_with(ENV_PREFIX) {
+                return Err(format!(
+                    "Only environment variables starting with {ENV_PREFIX} can be referenced, \
+                     found `{var}` in {raw}"
+                ));
+            }
+            match std::env::var(var) {
+                Ok(value) => result.push_str(&value),
+                Err(_) => return Err(format!("Environment variable {var} is not set (in {raw})")),
+            }
+            rest = &rest[start + 2 + len + 1..];
+        }
+        result.push_str(rest);
+        Url::parse(&result).map_err(|e| format!("Invalid URL {result}: {e}"))
+    }
+}
+
+impl From<Url> for ManifestUrl {
+    fn from(url: Url) -> Self {
+        Self(url.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for ManifestUrl {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(de)?;
+        // URLs without references can be checked right away, reporting the error with its span.
+        if !raw.contains("${") {
+            Url::parse(&raw).map_err(|e| D::Error::custom(format!("{e:?}")))?;
+        }
+        Ok(Self(raw))
+    }
+}
+
+impl Serialize for ManifestUrl {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(s)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
 }
 
-fn serialize_url<S: Serializer>(url: &Url, s: S) -> Result<S::Ok, S::Error> {
-    url.as_str().serialize(s)
+fn serialize_true<S: Serializer>(_: &(), s: S) -> Result<S::Ok, S::Error> {
+    true.serialize(s)
 }
 
 fn deserialize_true<'de, D: Deserializer<'de>>(de: D) -> Result<(), D::Error> {
@@ -364,3 +1539,535 @@ fn deserialize_true<'de, D: Deserializer<'de>>(de: D) -> Result<(), D::Error> {
         Err(D::Error::custom("must be true"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::HELLO_SHA256;
+
+    #[test]
+    fn format_keeps_comments_above_entries() {
+        let source = r#"# Top comment
+[[files]]
+name = "b.tar.gz"
+source = "https://example.com/b.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+
+# Comment of a
+[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+"#;
+        let formatted = canonical_format(source).unwrap();
+        assert!(
+            formatted.starts_with("# Top comment\n# Comment of a\n[[files]]\nname = \"a.tar.gz\"")
+        );
+    }
+
+    #[test]
+    fn format_keeps_comments_inside_entries() {
+        let source = r#"[[files]]
+name = "a.tar.gz"
+license = "MIT" # Checked upstream
+# Pinned until the next release.
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+"#;
+        let formatted = canonical_format(source).unwrap();
+        assert!(formatted.contains("\n# Pinned until the next release.\nsource = "));
+        assert!(formatted.contains("\nlicense = \"MIT\" # Checked upstream\n"));
+        assert_eq!(canonical_format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn format_refuses_comments_it_cannot_move() {
+        // Inline tables are rewritten as standard tables, which have nowhere to keep the comment.
+        let source = r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+tags = { team = "infra" } # Billing
+"#;
+        let error = canonical_format(source).unwrap_err();
+        assert!(error.to_string().contains("can't be preserved"));
+    }
+
+    #[test]
+    fn tag_characters() {
+        let tags = |key: &str, value: &str| BTreeMap::from([(key.to_string(), value.to_string())]);
+        assert_eq!(
+            tags_error(&tags("team", "infra/ci-mirrors@rust-lang.org")),
+            None
+        );
+        assert_eq!(tags_error(&tags("équipe", "infra 2")), None);
+        assert!(
+            tags_error(&tags("team", "infra&ops"))
+                .unwrap()
+                .contains("'&'")
+        );
+        assert!(
+            tags_error(&tags("aws:team", "infra"))
+                .unwrap()
+                .contains("reserved")
+        );
+    }
+
+    #[test]
+    fn load_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = load_manifests(&dir.path().join("files"), &LoadOptions::default())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("doesn't exist"));
+    }
+
+    #[test]
+    fn load_reports_errors_of_all_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.toml"), "[[files]\nname = \"a.tar.gz\"\n").unwrap();
+        std::fs::write(
+            dir.path().join("b.toml"),
+            r#"[[files]]
+name = "b.tar.gz"
+source = "https://example.com/b.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+
+[[files]]
+name = "c.tar.gz"
+source = "https://example.com/other.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9825"
+license = "MIT"
+"#,
+        )
+        .unwrap();
+
+        let (files, errors) = load_manifests(dir.path(), &LoadOptions::default()).unwrap();
+        assert!(files.iter().any(|file| file.name == "b.tar.gz"));
+        // The broken manifest doesn't hide the errors of the other one.
+        assert_eq!(errors.len(), 2, "{errors:#?}");
+        assert!(matches!(errors[0].kind, ValidationErrorKind::Parse));
+        assert_eq!(errors[0].locations[0].file, dir.path().join("a.toml"));
+        assert!(errors[1].message.contains("doesn't match the `name` field"));
+        assert_eq!(errors[1].locations[0].file, dir.path().join("b.toml"));
+    }
+
+    /// Errors of a manifest with a single entry with this `license`.
+    fn license_errors(license: &str, options: &LoadOptions) -> Vec<ValidationError> {
+        let source = format!(
+            r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "{license}"
+"#
+        );
+        load_manifest_source(Path::new("a.toml"), source, options)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn require_license() {
+        let options = LoadOptions {
+            require_license: true,
+            ..LoadOptions::default()
+        };
+        assert!(license_errors("MIT OR Apache-2.0", &options).is_empty());
+        for license in ["", "  "] {
+            let errors = license_errors(license, &options);
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].message, "The `license` field cannot be empty");
+        }
+        assert!(license_errors("", &LoadOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn git_archive_urls() {
+        let url = |repo: &str, rev: &str| {
+            git_archive_url(&Url::parse(repo).unwrap(), rev).map(|url| url.to_string())
+        };
+        assert_eq!(
+            url("https://github.com/rust-lang/libc.git", "0.2.170").unwrap(),
+            "https://github.com/rust-lang/libc/archive/0.2.170.tar.gz"
+        );
+        assert_eq!(
+            url("https://gitlab.gnome.org/GNOME/glib/", "2.80.0").unwrap(),
+            "https://gitlab.gnome.org/GNOME/glib/-/archive/2.80.0/glib-2.80.0.tar.gz"
+        );
+        assert!(url("https://github.com/rust-lang/libc", "refs/tags/x").is_err());
+        assert!(
+            url("https://sourceware.org/git/glibc.git", "2.40")
+                .unwrap_err()
+                .contains("only GitHub and GitLab")
+        );
+    }
+
+    #[test]
+    fn load_git_source() {
+        let source = r#"[[files]]
+name = "libc-0.2.170.tar.gz"
+git = { repo = "https://github.com/rust-lang/libc", rev = "0.2.170" }
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT OR Apache-2.0"
+"#;
+        let (files, errors) =
+            load_manifest_source(Path::new("a.toml"), source.into(), &LoadOptions::default())
+                .unwrap();
+        assert!(errors.is_empty(), "{errors:#?}");
+        let Source::Git { repo, rev, archive } = &files[0].source else {
+            panic!("not a git source");
+        };
+        assert_eq!(repo.as_str(), "https://github.com/rust-lang/libc");
+        assert_eq!(rev, "0.2.170");
+        assert_eq!(files[0].source.url(), Some(archive));
+    }
+
+    /// Errors of a manifest with a single entry with this `source` field.
+    fn source_errors(source: &str, options: &LoadOptions) -> Vec<ValidationError> {
+        let manifest = format!(
+            r#"[[files]]
+name = "a.tar.gz"
+{source}
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+"#
+        );
+        load_manifest_source(Path::new("a.toml"), manifest, options)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn deny_insecure_urls() {
+        let deny = LoadOptions {
+            deny_insecure_urls: true,
+            ..LoadOptions::default()
+        };
+        let https = r#"source = "https://example.com/a.tar.gz""#;
+        let http = r#"source = "http://example.com/a.tar.gz""#;
+        let git = r#"git = { repo = "http://gitlab.example.com/group/a", rev = "v1" }"#;
+        assert!(source_errors(https, &deny).is_empty());
+        // Only a warning by default.
+        assert!(source_errors(http, &LoadOptions::default()).is_empty());
+        assert!(source_errors(git, &LoadOptions::default()).is_empty());
+
+        let errors = source_errors(http, &deny);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "The `source` is downloaded over HTTP instead of HTTPS"
+        );
+        let errors = source_errors(git, &deny);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "The `git` repository is served over HTTP instead of HTTPS"
+        );
+    }
+
+    #[test]
+    fn trailing_space_in_hash() {
+        let source = r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 "
+license = "MIT"
+"#;
+        let (_, errors) =
+            load_manifest_source(Path::new("a.toml"), source.into(), &LoadOptions::default())
+                .unwrap();
+        assert_eq!(errors.len(), 1, "{errors:#?}");
+        assert_eq!(
+            errors[0].rendered,
+            "The `sha256` field has leading or trailing whitespace:\n\
+             # a.toml (line 1)\n\
+             [[files]]\n\
+             name = \"a.tar.gz\"\n\
+             source = \"https://example.com/a.tar.gz\"\n\
+             sha256 = \"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 \"\n\
+             license = \"MIT\"\n"
+        );
+
+        // --fix trims the value in place.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.toml");
+        std::fs::write(&path, source).unwrap();
+        let options = LoadOptions {
+            fix: true,
+            ..LoadOptions::default()
+        };
+        let (files, errors) = load_manifests(&path, &options).unwrap();
+        assert!(errors.is_empty(), "{errors:#?}");
+        assert_eq!(files[0].sha256, HELLO_SHA256);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            source.replace(" \"\n", "\"\n")
+        );
+    }
+
+    #[test]
+    fn extension_policies() {
+        let denied = LoadOptions {
+            denied_extensions: vec!["exe".into(), ".msi".into()],
+            ..LoadOptions::default()
+        };
+        assert!(denied.extension_error("tool/setup.tar.gz").is_none());
+        assert!(
+            denied
+                .extension_error("tool/setup.EXE")
+                .unwrap()
+                .contains("the `.exe` extension")
+        );
+        assert!(
+            denied
+                .extension_error("tool/setup.msi")
+                .unwrap()
+                .contains("the `.msi` extension")
+        );
+
+        let allowed = LoadOptions {
+            allowed_extensions: vec!["tar.gz".into(), "zip".into()],
+            ..LoadOptions::default()
+        };
+        assert!(allowed.extension_error("tool/src.tar.gz").is_none());
+        assert!(allowed.extension_error("tool/src.ZIP").is_none());
+        assert!(allowed.extension_error("tool/src.tar.xz").is_some());
+        // `tar.gz` doesn't allow files merely ending with `gz`.
+        assert!(allowed.extension_error("tool/srctar.gz").is_some());
+    }
+
+    #[test]
+    fn allow_extension_overrides_the_policies() {
+        let options = LoadOptions {
+            denied_extensions: vec!["exe".into()],
+            ..LoadOptions::default()
+        };
+        let manifest = |allow_extension: bool| {
+            format!(
+                r#"[[files]]
+name = "setup.exe"
+source = "https://example.com/setup.exe"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+allow-extension = {allow_extension}
+"#
+            )
+        };
+        let (_, errors) =
+            load_manifest_source(Path::new("a.toml"), manifest(false), &options).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("`.exe` extension"));
+        let (_, errors) =
+            load_manifest_source(Path::new("a.toml"), manifest(true), &options).unwrap();
+        assert!(errors.is_empty(), "{errors:#?}");
+    }
+
+    #[test]
+    fn stable_error_order() {
+        let mut source = String::new();
+        for (name, url, hash) in [
+            ("a.tar.gz", "a", "1"),
+            ("b.tar.gz", "b", "2"),
+            ("a.tar.gz", "c", "3"),
+            ("d.tar.gz", "b", "4"),
+            ("e.tar.gz", "e", "2"),
+            ("f.tar.gz", "f", "3"),
+        ] {
+            source.push_str(&format!(
+                "[[files]]\nname = \"{name}\"\nsource = \"https://example.com/{url}/{name}\"\n\
+                 sha256 = \"{}\"\nlicense = \"MIT\"\n\n",
+                hash.repeat(64)
+            ));
+        }
+        let load = || {
+            let (_, errors) =
+                load_manifest_source(Path::new("a.toml"), source.clone(), &LoadOptions::default())
+                    .unwrap();
+            errors
+                .iter()
+                .map(|error| error.message.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // The duplicates are found through hash maps, which are seeded differently every time.
+        let first = load();
+        assert_eq!(first.len(), 3, "{first:#?}");
+        for _ in 0..20 {
+            assert_eq!(load(), first);
+        }
+        assert!(first[0].contains("same CDN path `a.tar.gz`"));
+    }
+
+    #[test]
+    fn normalize_urls() {
+        let normalize = |url: &str| normalize_url(&Url::parse(url).unwrap()).to_string();
+        let expected = "https://example.com/dist/foo-1.0.tar.gz";
+        for url in [
+            "https://example.com/dist/foo-1.0.tar.gz",
+            "HTTPS://Example.COM:443/dist/foo-1.0.tar.gz",
+            "https://example.com/dist/foo-1.0.tar.gz#sha256",
+            "https://example.com/dist/foo-1.0.tar.gz/",
+            "https://example.com/dist/%66oo%2D1.0.tar.gz",
+        ] {
+            assert_eq!(normalize(url), expected, "{url}");
+        }
+        // Escapes of reserved characters change the resource, so only their case is normalized.
+        assert_eq!(
+            normalize("https://example.com/a%2fb%2Bc"),
+            "https://example.com/a%2Fb%2Bc"
+        );
+        assert_eq!(
+            normalize("https://example.com/100%"),
+            "https://example.com/100%"
+        );
+        assert_eq!(normalize("https://example.com/"), "https://example.com/");
+        // The query is kept as is.
+        assert_eq!(
+            normalize("https://example.com/get?b=1&a=2"),
+            "https://example.com/get?b=1&a=2"
+        );
+    }
+
+    #[test]
+    fn unknown_field_line() {
+        let source = r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+
+[[files]]
+name = "b.tar.gz"
+source = "https://example.com/b.tar.gz"
+sha265 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+"#;
+        assert_eq!(unknown_field(source), Some(("sha265".to_string(), 10)));
+
+        let (_, errors) =
+            load_manifest_source(Path::new("a.toml"), source.into(), &LoadOptions::default())
+                .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, ValidationErrorKind::Parse));
+        assert_eq!(errors[0].locations[0].line, Some(10));
+        assert_eq!(
+            errors[0].rendered,
+            "Unknown field `sha265`:\n\
+             # a.toml (line 10)\n\
+             sha265 = \"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\"\n"
+        );
+    }
+
+    #[test]
+    fn skip_validation_on_managed_entry() {
+        let source = r#"[[files]]
+name = "a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+
+[[files]]
+name = "mirror/a.tar.gz"
+source = "https://example.com/a.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+license = "MIT"
+skip-validation = true
+
+[[files]]
+name = "other/b.tar.gz"
+source = "https://example.com/c.tar.gz"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9825"
+license = "MIT"
+skip-validation = true
+"#;
+        let (files, errors) =
+            load_manifest_source(Path::new("a.toml"), source.into(), &LoadOptions::default())
+                .unwrap();
+        // The shared URL and hash aren't reported, and the entry is still mirrored.
+        assert!(files.iter().any(|file| file.name == "mirror/a.tar.gz"));
+        // The other errors of the skipped entries are still reported, pointing at them.
+        assert_eq!(errors.len(), 1, "{errors:#?}");
+        assert!(errors[0].message.contains("doesn't match the `name` field"));
+        assert_eq!(errors[0].locations[0].line, Some(14));
+    }
+
+    fn path_errors(names: &[&str], options: &LoadOptions) -> Vec<ValidationError> {
+        let manifest = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                format!(
+                    r#"[[files]]
+name = "{name}"
+source = "https://example.com/{i}/bar"
+sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b982{i}"
+license = "MIT"
+"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        load_manifest_source(Path::new("a.toml"), manifest, options)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn conflicting_trailing_slash() {
+        let errors = path_errors(&["foo/bar", "foo/bar/"], &LoadOptions::default());
+        // The name of `foo/bar/` also doesn't match its URL, which is reported separately.
+        let conflicts = errors
+            .iter()
+            .filter(|error| matches!(error.kind, ValidationErrorKind::ConflictingPath))
+            .collect::<Vec<_>>();
+        assert_eq!(conflicts.len(), 1, "{errors:#?}");
+        assert_eq!(
+            conflicts[0].message,
+            "The CDN paths `foo/bar`, `foo/bar/` only differ by a trailing slash"
+        );
+        let lines = conflicts[0]
+            .locations
+            .iter()
+            .map(|location| location.line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [Some(1), Some(7)]);
+    }
+
+    #[test]
+    fn conflicting_case() {
+        let names = ["Foo/bar", "foo/bar"];
+        assert!(path_errors(&names, &LoadOptions::default()).is_empty());
+
+        let options = LoadOptions {
+            case_insensitive_paths: true,
+            ..LoadOptions::default()
+        };
+        let errors = path_errors(&names, &options);
+        assert_eq!(errors.len(), 1, "{errors:#?}");
+        assert!(matches!(
+            errors[0].kind,
+            ValidationErrorKind::ConflictingPath
+        ));
+        assert_eq!(errors[0].locations.len(), 2);
+    }
+
+    #[test]
+    fn exact_duplicate_path_is_not_conflicting() {
+        let errors = path_errors(&["foo/bar", "foo/bar"], &LoadOptions::default());
+        assert_eq!(errors.len(), 1, "{errors:#?}");
+        assert!(matches!(errors[0].kind, ValidationErrorKind::DuplicatePath));
+    }
+
+    #[test]
+    fn load_empty_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, errors) = load_manifests(dir.path(), &LoadOptions::default()).unwrap();
+        assert!(files.is_empty());
+        assert!(errors.is_empty());
+    }
+}
@@ -0,0 +1,960 @@
+use crate::downloader::{self, Downloader, Hashes, format_size};
+use crate::http::HttpArgs;
+use crate::manifest::{self, LoadOptions, MirrorFile, Source, ValidationError};
+use crate::status_cache::StatusCache;
+use crate::storage::{self, CdnReader, FileStatus, MissingPart, S3Storage, Storage};
+use crate::utils::{PROGRESS_TARGET, to_sri};
+use crate::{archive, index};
+use anyhow::{Error, anyhow, bail};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// Configuration of a [`Mirror`].
+pub struct MirrorConfig {
+    /// Base URL of the CDN where mirrored files are served.
+    pub cdn_url: String,
+    /// Names of the S3 buckets containing the files, using `name:region` for buckets outside of
+    /// the default region.
+    pub s3_buckets: Vec<String>,
+    /// Endpoint of an S3-compatible service to use instead of AWS.
+    pub s3_endpoint_url: Option<String>,
+    /// Number of times to retry S3 requests failing with transient errors.
+    pub s3_retries: u32,
+    /// Number of times to retry checking the status of a file when it fails with a transient
+    /// error, on top of the retries of the individual S3 requests.
+    pub status_retries: u32,
+    /// Only read the files from the CDN, without requiring credentials. Uploading is not
+    /// possible in this mode.
+    pub read_only: bool,
+    /// Also upload a `.sri` file next to each uploaded file.
+    pub write_sri: bool,
+    /// Tag the uploaded objects with whether they are managed, the first segment of their name,
+    /// and the `tags` of their entry.
+    pub tag_objects: bool,
+    /// Read back the uploaded objects to check they were stored correctly.
+    pub verify_after_upload: bool,
+    /// Record the hashes of the uploaded objects in their metadata, and check the status of the
+    /// files with their ETag instead of reading their `.sha256` file when possible.
+    pub etag_status: bool,
+    /// Maximum number of files to check or upload in parallel.
+    pub jobs: usize,
+    /// Check that there is enough disk space to download the files before downloading them.
+    pub space_check: bool,
+    /// Store the downloaded files compressed until they are uploaded, trading CPU for disk
+    /// space.
+    pub compress_temp: bool,
+    /// Directory the temporary directory holding the downloaded files is created in, instead of
+    /// the system temporary directory.
+    pub cache_dir: Option<PathBuf>,
+    /// Index written by `generate-index`, used to find the files already uploaded under another
+    /// path with the same content. They are still downloaded and verified, but are copied within
+    /// the buckets instead of being uploaded again.
+    pub dedup_index: Option<PathBuf>,
+    /// Check that the files already uploaded were mirrored from the upstream file named in the
+    /// manifests, catching `source` or `rename-from` edits after the upload.
+    pub verify_present: bool,
+    /// Also upload a `.members.json` file next to each uploaded tar archive, with the hashes of
+    /// the files it contains.
+    pub member_hashes: bool,
+    /// Maximum number of bytes to download in total. The remaining files are reported as errors
+    /// once it's exceeded.
+    pub max_download_bytes: Option<u64>,
+    /// Stop checking the files at the first error, instead of collecting all of them.
+    pub fail_fast: bool,
+    /// Only check that the upstreams of the missing files are reachable with HEAD requests,
+    /// instead of downloading them. Only possible in read-only mode.
+    pub no_download: bool,
+    /// Path under which all the files are stored on the CDN, for example to host a staging
+    /// mirror next to the production one.
+    pub prefix: Option<String>,
+    /// File caching which files are present on the CDN, to avoid checking them on every run.
+    /// Only used in read-only mode, so that the uploads are always based on the real status.
+    pub status_cache: Option<PathBuf>,
+    /// Updated as the files are checked, downloaded and uploaded, to report how far an
+    /// interrupted run got.
+    pub progress: Arc<Progress>,
+    pub http: HttpArgs,
+}
+
+/// How far a run got, shared with the caller so that it can be reported when the run is
+/// interrupted.
+#[derive(Default)]
+pub struct Progress {
+    phase: Mutex<Phase>,
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    fn start(&self, phase: Phase, total: usize) {
+        *self.phase.lock().unwrap() = phase;
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = *self.phase.lock().unwrap();
+        if let Phase::Loading = phase {
+            return f.write_str("loading the manifests");
+        }
+        let verb = match phase {
+            Phase::Loading => unreachable!(),
+            Phase::CheckingStatus => "checking the status of",
+            Phase::Downloading => "downloading",
+            Phase::Uploading => "uploading",
+        };
+        write!(
+            f,
+            "{verb} the files ({} of {} done)",
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+enum Phase {
+    #[default]
+    Loading,
+    CheckingStatus,
+    Downloading,
+    Uploading,
+}
+
+/// Checks and uploads the mirrored files.
+pub struct Mirror {
+    storage: Arc<Storage>,
+    downloader: Arc<Downloader>,
+    concurrency_limiter: Arc<Semaphore>,
+    jobs: usize,
+    status_retries: u32,
+    write_sri: bool,
+    tag_objects: bool,
+    space_check: bool,
+    verify_present: bool,
+    member_hashes: bool,
+    max_download_bytes: Option<u64>,
+    fail_fast: bool,
+    no_download: bool,
+    /// Normalized to never start or end with a slash, and to not be empty.
+    prefix: Option<String>,
+    status_cache: Option<PathBuf>,
+    /// Names of the files of the dedup index, keyed by their hash. Empty without an index.
+    dedup_index: HashMap<String, Vec<String>>,
+    progress: Arc<Progress>,
+}
+
+impl Mirror {
+    pub async fn new(config: MirrorConfig) -> Result<Self, Error> {
+        if config.no_download && !config.read_only {
+            bail!("the files must be downloaded to upload them");
+        }
+        if config.status_cache.is_some() && !config.read_only {
+            warn!("the status cache is only used in read-only mode, ignoring it");
+        }
+        Ok(Self {
+            storage: Arc::new(if config.read_only {
+                Storage::ReadOnly(CdnReader::new(config.cdn_url, &config.http)?)
+            } else {
+                Storage::ReadWrite(Box::new(
+                    S3Storage::new(
+                        &config.s3_buckets,
+                        config.s3_endpoint_url.as_deref(),
+                        config.s3_retries,
+                        config.verify_after_upload,
+                        config.etag_status,
+                    )
+                    .await?,
+                ))
+            }),
+            downloader: Arc::new(
+                Downloader::new(&config.http, config.cache_dir.as_deref())?
+                    .compress_storage(config.compress_temp)
+                    .s3_endpoint_url(config.s3_endpoint_url.clone()),
+            ),
+            concurrency_limiter: Arc::new(Semaphore::new(config.jobs)),
+            jobs: config.jobs,
+            status_retries: config.status_retries,
+            write_sri: config.write_sri,
+            tag_objects: config.tag_objects,
+            space_check: config.space_check,
+            verify_present: config.verify_present,
+            member_hashes: config.member_hashes,
+            max_download_bytes: config.max_download_bytes,
+            fail_fast: config.fail_fast,
+            no_download: config.no_download,
+            prefix: storage::normalize_prefix(config.prefix),
+            status_cache: config.status_cache.filter(|_| config.read_only),
+            dedup_index: match &config.dedup_index {
+                Some(path) => index::load_names_by_hash(path)?,
+                None => HashMap::new(),
+            },
+            progress: config.progress,
+        })
+    }
+
+    /// Check that the credentials can upload files, before spending time downloading them. Does
+    /// nothing in read-only mode.
+    pub async fn check_credentials(&self) -> Result<(), Error> {
+        match &*self.storage {
+            Storage::ReadOnly(_) => Ok(()),
+            Storage::ReadWrite(s3) => s3.check_write_access().await,
+        }
+    }
+
+    /// Path of the file on the CDN, including the prefix.
+    fn key(&self, file: &MirrorFile) -> String {
+        storage::object_key(self.prefix.as_deref(), &file.name)
+    }
+
+    /// Paths of the files of the dedup index with the same content as `file`, under another name.
+    fn duplicates_of(&self, file: &MirrorFile) -> Vec<String> {
+        self.dedup_index
+            .get(&file.sha256)
+            .into_iter()
+            .flatten()
+            .filter(|name| **name != file.name)
+            .map(|name| storage::object_key(self.prefix.as_deref(), name))
+            .collect()
+    }
+
+    /// Load either a single manifest, or all the manifests in a directory (recursively).
+    /// Returns the files along with the validation errors found in the manifests.
+    pub fn load_manifests(
+        &self,
+        load_from: &Path,
+        options: &LoadOptions,
+    ) -> Result<(Vec<MirrorFile>, Vec<ValidationError>), Error> {
+        manifest::load_manifests(load_from, options)
+    }
+
+    /// Check which of the files need to be uploaded, and download them to ensure the uploads
+    /// will succeed. When `overwrite` is set, all the files are uploaded again, replacing the
+    /// existing objects.
+    pub async fn check_status(
+        &self,
+        files: Vec<MirrorFile>,
+        overwrite: bool,
+    ) -> Result<Plan, Error> {
+        info!(
+            "calculating the changes to execute ({} files, {} parallelism)...",
+            files.len(),
+            self.jobs
+        );
+
+        self.progress.start(Phase::CheckingStatus, files.len());
+
+        // Check the status of all files in parallel, except the ones cached as present.
+        let mut cache = self.status_cache.as_deref().map(StatusCache::load);
+        let mut cached = Vec::new();
+        let mut taskset = JoinSet::new();
+        for file in files {
+            let key = self.key(&file);
+            // The cache doesn't record the `.sha512` files, which must still be checked.
+            if let Some(cache) = &cache
+                && file.sha512.is_none()
+                && cache.is_present(&key, &file.sha256)
+            {
+                let sha256 = file.sha256.clone();
+                cached.push((file, Ok(FileStatus::Present { sha256 }), None));
+                continue;
+            }
+            let storage = self.storage.clone();
+            let concurrency_limiter = self.concurrency_limiter.clone();
+            let status_retries = self.status_retries;
+            let progress = self.progress.clone();
+            taskset.spawn(async move {
+                let _permit = concurrency_limiter.acquire().await.unwrap();
+                let status = storage.file_status_with_retries(&key, status_retries).await;
+                // A `sha512` can be added to the entry of a file already uploaded without it.
+                let sha512 = match &status {
+                    Ok(FileStatus::Present { .. }) if file.sha512.is_some() => {
+                        Some(storage.read_contents(&format!("{key}.sha512")).await)
+                    }
+                    _ => None,
+                };
+                progress.advance();
+                (file, status, sha512)
+            });
+        }
+
+        let mut errors = Vec::new();
+        let mut to_upload = Vec::new();
+        let mut sidecars_only = HashSet::new();
+        let mut to_verify = Vec::new();
+        let (mut checked, mut present, mut legacy) = (0, 0, 0);
+        for (file, status, sha512) in taskset.join_all().await.into_iter().chain(cached) {
+            checked += 1;
+            if let Some(cache) = &mut cache
+                && let Ok(FileStatus::Present { sha256 }) = &status
+                && *sha256 == file.sha256
+            {
+                cache.insert(self.key(&file), sha256.clone());
+            }
+            let name = &file.name;
+            // Failing to check the status is reported even when overwriting, rather than silently
+            // uploading the file again.
+            let status = status?;
+            if overwrite {
+                warn!("{name} will be overwritten");
+                to_upload.push(file);
+                continue;
+            }
+            match status {
+                FileStatus::Inconsistent {
+                    missing: MissingPart::Sidecar,
+                } if matches!(file.source, Source::Legacy) => {
+                    legacy += 1;
+                    errors.push(PlanError::new(
+                        PlanErrorKind::UploadedWithoutTool,
+                        Some(name),
+                        format!("file {name} was already uploaded without this tool"),
+                    ));
+                }
+                FileStatus::Inconsistent {
+                    missing: MissingPart::Object { sha256 },
+                } if sha256 != file.sha256 => errors.push(PlanError::new(
+                    PlanErrorKind::ContentMismatch,
+                    Some(name),
+                    format!(
+                        "file {name} was partially uploaded with different content (the \
+                         manifest expects {}, {name}.sha256 contains {sha256})",
+                        file.sha256
+                    ),
+                )),
+                // The existing part is checked to match the file before uploading the missing
+                // one, so that interrupted uploads are healed.
+                FileStatus::Inconsistent { missing } => {
+                    match missing {
+                        MissingPart::Object { .. } => {
+                            warn!("{name} is missing, but not {name}.sha256")
+                        }
+                        MissingPart::Sidecar => warn!("{name}.sha256 is missing, but not {name}"),
+                    }
+                    to_upload.push(file);
+                }
+                FileStatus::Present { sha256 } if sha256 != file.sha256 => {
+                    // Showing the uploaded object helps telling whether the upstream changed or
+                    // the manifest is wrong.
+                    let object = match self.storage.object_info(&self.key(&file)).await {
+                        Ok(Some(info)) => info.to_string(),
+                        Ok(None) => "missing".to_string(),
+                        Err(err) => format!("unknown ({err:#})"),
+                    };
+                    errors.push(PlanError::new(
+                        PlanErrorKind::ContentMismatch,
+                        Some(name),
+                        format!(
+                            "file {name} was already uploaded with different content (the \
+                             manifest expects {}, {name}.sha256 contains {sha256}, the object \
+                             is {object})",
+                            file.sha256
+                        ),
+                    ))
+                }
+                FileStatus::CorruptSidecar { content } => errors.push(PlanError::new(
+                    PlanErrorKind::CorruptSidecar,
+                    Some(name),
+                    format!(
+                        "file {name} has a corrupt {name}.sha256 (containing {content:?}), \
+                         ask the infra team to replace its content with {}",
+                        file.sha256
+                    ),
+                )),
+                FileStatus::Missing => to_upload.push(file),
+                FileStatus::Present { .. } => match sha512 {
+                    Some(Ok(None)) => {
+                        warn!("{name}.sha512 is missing");
+                        sidecars_only.insert(name.clone());
+                        to_upload.push(file);
+                    }
+                    Some(Ok(Some(recorded))) if Some(recorded.trim()) != file.sha512.as_deref() => {
+                        errors.push(PlanError::new(
+                            PlanErrorKind::ContentMismatch,
+                            Some(name),
+                            format!(
+                                "file {name} was already uploaded with a different SHA-512 (the \
+                                 manifest expects {}, {name}.sha512 contains {})",
+                                file.sha512.as_deref().unwrap_or_default(),
+                                recorded.trim()
+                            ),
+                        ))
+                    }
+                    Some(Err(err)) => errors.push(PlanError::new(
+                        PlanErrorKind::Check,
+                        Some(name),
+                        format!("{err:?}"),
+                    )),
+                    Some(Ok(Some(_))) | None => {
+                        present += 1;
+                        if self.verify_present
+                            && let Some(url) = file.source.url()
+                        {
+                            // The `.sha256` file already matched the manifest to get here, so
+                            // only the upstream name is left to check.
+                            let from_url = url.path().split('/').next_back().unwrap_or_default();
+                            match &file.rename_from {
+                                Some(rename_from) if rename_from != from_url => {
+                                    errors.push(PlanError::new(
+                                        PlanErrorKind::UpstreamNameMismatch,
+                                        Some(name),
+                                        format!(
+                                            "file {name} is renamed from `{rename_from}`, but its \
+                                             `source` points to `{from_url}`"
+                                        ),
+                                    ))
+                                }
+                                rename_from => {
+                                    let expected = rename_from.as_deref().unwrap_or(from_url);
+                                    to_verify.push((
+                                        self.key(&file),
+                                        file.name.clone(),
+                                        expected.to_string(),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+            if self.fail_fast && !errors.is_empty() {
+                break;
+            }
+        }
+        if !self.fail_fast || errors.is_empty() {
+            errors.append(&mut self.check_upstream_names(to_verify).await);
+        }
+
+        if let Some(cache) = &cache
+            && let Err(err) = cache.save()
+        {
+            warn!("failed to save the status cache: {err:?}");
+        }
+
+        // We download eagerly to be able to detect errors during the check phase. Files are only
+        // stored when they can be uploaded afterwards.
+        let read_only = matches!(*self.storage, Storage::ReadOnly(_));
+        if self.fail_fast && !errors.is_empty() {
+            to_upload.clear();
+        }
+        if self.no_download {
+            let (unverified, download_errors, not_downloaded) =
+                self.check_reachable(to_upload).await;
+            return Ok(Plan {
+                errors,
+                download_errors,
+                not_downloaded,
+                unverified,
+                checked,
+                present,
+                legacy,
+                to_upload: Vec::new(),
+                sidecars_only,
+                overwrite,
+            });
+        }
+        if !read_only && self.space_check {
+            self.check_disk_space(&to_upload).await?;
+        }
+        let mut downloaded = Vec::new();
+        let mut download_errors = Vec::new();
+        let mut not_downloaded = Vec::new();
+        let mut downloaded_bytes = 0;
+        self.progress.start(Phase::Downloading, to_upload.len());
+        let mut to_upload = to_upload.into_iter();
+        for file in to_upload.by_ref() {
+            // Files only missing their `.sha512` file are verified without being stored.
+            let result = if read_only || sidecars_only.contains(&file.name) {
+                self.downloader.check(&file).await
+            } else {
+                self.downloader.download(&file).await
+            };
+            self.progress.advance();
+            match result {
+                Ok(hashes) => {
+                    downloaded_bytes += hashes.len as u64;
+                    downloaded.push((file, hashes));
+                }
+                Err(err) if self.fail_fast => {
+                    download_errors.push(PlanError::new(
+                        PlanErrorKind::Download,
+                        Some(&file.name),
+                        format!("{err:?}"),
+                    ));
+                    not_downloaded.push(file.name);
+                    return Ok(Plan {
+                        errors,
+                        download_errors,
+                        not_downloaded,
+                        unverified: Vec::new(),
+                        checked,
+                        present,
+                        legacy,
+                        to_upload: downloaded,
+                        sidecars_only,
+                        overwrite,
+                    });
+                }
+                Err(err) => {
+                    download_errors.push(PlanError::new(
+                        PlanErrorKind::Download,
+                        Some(&file.name),
+                        format!("{err:?}"),
+                    ));
+                    not_downloaded.push(file.name);
+                }
+            }
+            if let Some(max) = self.max_download_bytes
+                && downloaded_bytes > max
+            {
+                break;
+            }
+        }
+        let skipped = to_upload.map(|file| file.name).collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            download_errors.push(PlanError::new(
+                PlanErrorKind::DownloadLimit,
+                None,
+                format!(
+                    "stopped downloading after {} ({} files), exceeding --max-download-bytes; \
+                     {} files were not downloaded: {}",
+                    format_size(downloaded_bytes as usize),
+                    downloaded.len(),
+                    skipped.len(),
+                    skipped.join(", ")
+                ),
+            ));
+        }
+
+        not_downloaded.extend(skipped);
+
+        Ok(Plan {
+            errors,
+            download_errors,
+            not_downloaded,
+            unverified: Vec::new(),
+            checked,
+            present,
+            legacy,
+            to_upload: downloaded,
+            sidecars_only,
+            overwrite,
+        })
+    }
+
+    /// Check that the uploaded files were mirrored from the expected upstream file names, as
+    /// recorded in the metadata of the objects. Objects without their source recorded are
+    /// skipped.
+    async fn check_upstream_names(&self, files: Vec<(String, String, String)>) -> Vec<PlanError> {
+        let mut taskset = JoinSet::new();
+        for (key, name, expected) in files {
+            let storage = self.storage.clone();
+            let concurrency_limiter = self.concurrency_limiter.clone();
+            taskset.spawn(async move {
+                let _permit = concurrency_limiter.acquire().await.unwrap();
+                match storage.upstream_file_name(&key).await {
+                    Ok(Some(recorded)) if recorded != expected => Some(PlanError::new(
+                        PlanErrorKind::UpstreamNameMismatch,
+                        Some(&name),
+                        format!(
+                            "file {name} was mirrored from an upstream file named \
+                             `{recorded}`, but the manifest points to `{expected}` (check its \
+                             `source` and `rename-from`)"
+                        ),
+                    )),
+                    Ok(_) => None,
+                    Err(err) => Some(PlanError::new(
+                        PlanErrorKind::Check,
+                        Some(&name),
+                        format!("{err:?}"),
+                    )),
+                }
+            });
+        }
+        taskset.join_all().await.into_iter().flatten().collect()
+    }
+
+    /// Check that the upstreams of the files are reachable with HEAD requests, without downloading
+    /// them. Returns the names of the reachable files, the errors, and the names of the
+    /// unreachable files.
+    async fn check_reachable(
+        &self,
+        files: Vec<MirrorFile>,
+    ) -> (Vec<String>, Vec<PlanError>, Vec<String>) {
+        let mut taskset = JoinSet::new();
+        for (index, file) in files.into_iter().enumerate() {
+            let downloader = self.downloader.clone();
+            let concurrency_limiter = self.concurrency_limiter.clone();
+            taskset.spawn(async move {
+                let _permit = concurrency_limiter.acquire().await.unwrap();
+                let error = match file.source.url() {
+                    None => Some(format!("file {} has no upstream to check", file.name)),
+                    Some(url) => match downloader.head_status(url).await {
+                        Ok(Some(status)) if status.is_success() => None,
+                        Ok(Some(status)) => Some(format!(
+                            "the upstream of {} is unreachable: {url} returned {status}",
+                            file.name
+                        )),
+                        Ok(None) => {
+                            warn!("cannot check whether {url} is reachable without downloading it");
+                            None
+                        }
+                        Err(err) => Some(format!("{err:?}")),
+                    },
+                };
+                (index, file.name, error)
+            });
+        }
+
+        // The checks complete in any order, so sort them back in the order of the files.
+        let mut results = taskset.join_all().await;
+        results.sort_by_key(|(index, _, _)| *index);
+        let (mut reachable, mut errors, mut unreachable) = (Vec::new(), Vec::new(), Vec::new());
+        for (_, name, error) in results {
+            match error {
+                None => reachable.push(name),
+                Some(message) => {
+                    errors.push(PlanError::new(
+                        PlanErrorKind::Unreachable,
+                        Some(&name),
+                        message,
+                    ));
+                    unreachable.push(name);
+                }
+            }
+        }
+        (reachable, errors, unreachable)
+    }
+
+    /// Fail early if the files to download don't fit on the disk, rather than midway through the
+    /// downloads. Files whose size is unknown are ignored.
+    async fn check_disk_space(&self, files: &[MirrorFile]) -> Result<(), Error> {
+        let mut taskset = JoinSet::new();
+        for file in files {
+            let Some(url) = file.source.url().cloned() else {
+                debug!("the size of {} is unknown", file.name);
+                continue;
+            };
+            let name = file.name.clone();
+            let downloader = self.downloader.clone();
+            let concurrency_limiter = self.concurrency_limiter.clone();
+            taskset.spawn(async move {
+                let _permit = concurrency_limiter.acquire().await.unwrap();
+                match downloader.content_length(&url).await {
+                    Ok(Some(len)) => len,
+                    Ok(None) => {
+                        debug!("the size of {name} is unknown");
+                        0
+                    }
+                    Err(err) => {
+                        debug!("failed to get the size of {name}: {err:?}");
+                        0
+                    }
+                }
+            });
+        }
+        let needed: u64 = taskset.join_all().await.into_iter().sum();
+
+        let available = self.downloader.available_space()?;
+        info!(
+            "downloading the files needs {} of disk space ({} available)",
+            format_size(needed as usize),
+            format_size(available as usize)
+        );
+        if needed > available {
+            bail!(
+                "not enough disk space to download the files ({} needed, {} available), \
+                 pass --cache-dir with a directory on a larger volume or upload the files in batches \
+                 with --only or --exclude",
+                format_size(needed as usize),
+                format_size(available as usize)
+            );
+        }
+        Ok(())
+    }
+
+    /// Keys the files of the plan will be uploaded to, along with their size in bytes. The plan
+    /// must have been created by this same [`Mirror`].
+    pub fn destinations<'a>(&'a self, plan: &'a Plan) -> impl Iterator<Item = (String, u64)> + 'a {
+        plan.to_upload.iter().map(|(file, hashes)| {
+            let key = self.key(file);
+            match plan.sidecars_only.contains(&file.name) {
+                true => (format!("{key}.sha512"), 128),
+                false => (key, hashes.len as u64),
+            }
+        })
+    }
+
+    /// Upload the files of the plan, which must have been created by this same [`Mirror`].
+    /// Returns the errors of the failed uploads.
+    pub async fn upload(&self, plan: Plan) -> Vec<Error> {
+        if matches!(*self.storage, Storage::ReadOnly(_)) {
+            return vec![anyhow!("cannot upload files in read-only mode")];
+        }
+        // Upload all files in parallel, with the same concurrency limit as the check phase.
+        // Errors are collected so that a single failure doesn't abort the other uploads. Dropping
+        // the returned future aborts the uploads in progress, before their `.sha256` file is
+        // written.
+        self.progress.start(Phase::Uploading, plan.to_upload.len());
+        let copied = Arc::new(AtomicUsize::new(0));
+        let copied_bytes = Arc::new(AtomicUsize::new(0));
+        let mut taskset = JoinSet::new();
+        for (file, hashes) in plan.to_upload {
+            let storage = self.storage.clone();
+            let concurrency_limiter = self.concurrency_limiter.clone();
+            let path = self.downloader.path_for(&file);
+            let compressed = self.downloader.compressed_path_for(&file);
+            let key = self.key(&file);
+            let write_sri = self.write_sri;
+            let tags = self.tag_objects.then(|| object_tags(&file));
+            // Copies can't replace existing objects, so the files are always uploaded when
+            // overwriting.
+            let duplicates = match plan.overwrite {
+                true => Vec::new(),
+                false => self.duplicates_of(&file),
+            };
+            let copied = copied.clone();
+            let copied_bytes = copied_bytes.clone();
+            let member_hashes = self.member_hashes;
+            let overwrite = plan.overwrite;
+            let sidecar_only = plan.sidecars_only.contains(&file.name);
+            let progress = self.progress.clone();
+            taskset.spawn(async move {
+                let _permit = concurrency_limiter.acquire().await.unwrap();
+                if sidecar_only && let Some(sha512) = &file.sha512 {
+                    info!(target: PROGRESS_TARGET, "uploading {key}.sha512...");
+                    storage
+                        .write_contents(&format!("{key}.sha512"), sha512.as_bytes(), false)
+                        .await?;
+                    progress.advance();
+                    return Ok(());
+                }
+                // Compressed files are only decompressed once the upload can start, so that at
+                // most `jobs` of them are stored uncompressed at the same time.
+                let decompressed = match compressed {
+                    Some(compressed) => Some(downloader::decompress_stored(compressed).await?),
+                    None => None,
+                };
+                let path = decompressed.as_deref().unwrap_or(&path);
+                if let Some(from) =
+                    copy_duplicate(&storage, &duplicates, &key, &file, tags.as_ref()).await
+                {
+                    info!(
+                        target: PROGRESS_TARGET,
+                        "copied {key} from {from}, which has the same content"
+                    );
+                    copied.fetch_add(1, Ordering::Relaxed);
+                    copied_bytes.fetch_add(hashes.len, Ordering::Relaxed);
+                } else {
+                    info!(target: PROGRESS_TARGET, "uploading {key}...");
+                    storage
+                        .upload_file(
+                            &key,
+                            path,
+                            &file.sha256,
+                            file.source.url(),
+                            tags.as_ref(),
+                            overwrite,
+                        )
+                        .await?;
+                }
+                if write_sri {
+                    storage
+                        .write_contents(
+                            &format!("{key}.sri"),
+                            to_sri(&hashes.raw).as_bytes(),
+                            overwrite,
+                        )
+                        .await?;
+                }
+                if member_hashes && let Some(format) = file.format {
+                    let archive = path.to_path_buf();
+                    let members = tokio::task::spawn_blocking(move || {
+                        archive::member_hashes(&archive, format)
+                    })
+                    .await??;
+                    if let Some(members) = members {
+                        storage
+                            .write_contents(
+                                &format!("{key}.members.json"),
+                                serde_json::to_string_pretty(&members)?.as_bytes(),
+                                overwrite,
+                            )
+                            .await?;
+                    }
+                }
+                if let Some(sha512) = &file.sha512 {
+                    storage
+                        .write_contents(&format!("{key}.sha512"), sha512.as_bytes(), overwrite)
+                        .await?;
+                }
+                // The `.sha256` file must be written last: its presence is what marks the file as
+                // uploaded, so any failure above must leave it missing.
+                storage
+                    .write_contents(
+                        &storage::sidecar_key(&key),
+                        file.sha256.as_bytes(),
+                        overwrite,
+                    )
+                    .await?;
+                progress.advance();
+                Ok::<_, Error>(())
+            });
+        }
+
+        let errors = taskset
+            .join_all()
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        let copied = copied.load(Ordering::Relaxed);
+        if copied > 0 {
+            info!(
+                "copied {copied} file(s) already in the buckets under another path, avoiding \
+                 uploading {}",
+                format_size(copied_bytes.load(Ordering::Relaxed))
+            );
+        }
+        errors
+    }
+}
+
+/// Copy to `key` the first of the `duplicates` that is fully uploaded with the content of `file`,
+/// instead of uploading it again. Returns the path it was copied from, or `None` if the file must
+/// be uploaded, including when the copy fails.
+async fn copy_duplicate(
+    storage: &Storage,
+    duplicates: &[String],
+    key: &str,
+    file: &MirrorFile,
+    tags: Option<&BTreeMap<String, String>>,
+) -> Option<String> {
+    for from in duplicates {
+        match storage.file_status(from).await {
+            Ok(FileStatus::Present { sha256 }) if sha256 == file.sha256 => {}
+            Ok(_) => continue,
+            Err(err) => {
+                debug!("failed to check the status of {from}: {err:#}");
+                continue;
+            }
+        }
+        return match storage
+            .copy_duplicate(from, key, file.source.url(), tags)
+            .await
+        {
+            Ok(()) => Some(from.clone()),
+            Err(err) => {
+                warn!("failed to copy {from} to {key}, uploading it instead: {err:#}");
+                None
+            }
+        };
+    }
+    None
+}
+
+/// S3 tags of the object storing `file`: the tags of its entry, along with whether it's managed
+/// and the first segment of its name (usually the tool it belongs to).
+fn object_tags(file: &MirrorFile) -> BTreeMap<String, String> {
+    let mut tags = file.tags.clone();
+    let managed = !matches!(file.source, Source::Legacy);
+    tags.insert("managed".into(), managed.to_string());
+    if let Some((tool, _)) = file.name.split_once('/') {
+        tags.insert("tool".into(), tool.into());
+    }
+    tags
+}
+
+/// Changes to apply, as calculated by [`Mirror::check_status`].
+pub struct Plan {
+    /// Errors found while checking the files. Uploading should not happen if any are present.
+    pub errors: Vec<PlanError>,
+    /// Errors found while downloading the files. Unlike [`Plan::errors`], the files downloaded
+    /// successfully can still be uploaded.
+    pub download_errors: Vec<PlanError>,
+    /// Names of the files that couldn't be downloaded, and won't be uploaded.
+    pub not_downloaded: Vec<String>,
+    /// Names of the files to upload whose upstream is reachable, but which were not downloaded
+    /// to check their content (with [`MirrorConfig::no_download`]).
+    pub unverified: Vec<String>,
+    /// Number of files whose status was checked.
+    pub checked: usize,
+    /// Number of files already uploaded, which are skipped.
+    pub present: usize,
+    /// Number of files uploaded without this tool.
+    pub legacy: usize,
+    to_upload: Vec<(MirrorFile, Hashes)>,
+    /// Names of the files to upload which are already present, and only miss their `.sha512`
+    /// file.
+    sidecars_only: HashSet<String>,
+    overwrite: bool,
+}
+
+impl Plan {
+    /// Files that will be uploaded.
+    pub fn files(&self) -> impl Iterator<Item = &MirrorFile> {
+        self.to_upload.iter().map(|(file, _)| file)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_upload.is_empty()
+    }
+}
+
+/// Problem found with a file while checking its status or downloading it. It's displayed as a
+/// human-readable message.
+#[derive(Debug)]
+pub struct PlanError {
+    pub kind: PlanErrorKind,
+    /// Name of the file the error refers to, missing for the errors about several files.
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl PlanError {
+    fn new(kind: PlanErrorKind, file: Option<&str>, message: String) -> Self {
+        Self {
+            kind,
+            file: file.map(String::from),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanErrorKind {
+    /// The file was uploaded before this tool managed the mirror.
+    UploadedWithoutTool,
+    /// The uploaded file doesn't match the manifest.
+    ContentMismatch,
+    /// The `.sha256` file doesn't contain a valid hash.
+    CorruptSidecar,
+    /// The file was mirrored from another upstream file than the one in the manifest.
+    UpstreamNameMismatch,
+    /// Checking the uploaded file failed.
+    Check,
+    /// The upstream of the file couldn't be reached.
+    Unreachable,
+    /// The file couldn't be downloaded or doesn't match the manifest.
+    Download,
+    /// The files were not downloaded, as `--max-download-bytes` was exceeded.
+    DownloadLimit,
+}
@@ -0,0 +1,28 @@
+use crate::utils::write_atomic;
+use anyhow::Error;
+use serde::Serialize;
+use std::path::Path;
+
+/// Results of an `upload` run, written to the file passed to `--report-file`.
+#[derive(Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RunReport {
+    pub(crate) success: bool,
+    pub(crate) checked: usize,
+    pub(crate) present: usize,
+    pub(crate) legacy: usize,
+    /// Names of the files that needed to be uploaded.
+    pub(crate) to_upload: Vec<String>,
+    pub(crate) uploaded: usize,
+    /// Names of the files that were not uploaded because they couldn't be downloaded.
+    pub(crate) not_downloaded: Vec<String>,
+    pub(crate) errors: Vec<String>,
+}
+
+impl RunReport {
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut content = serde_json::to_string_pretty(self)?;
+        content.push('\n');
+        write_atomic(path, content.as_bytes())
+    }
+}
@@ -0,0 +1,83 @@
+use crate::manifest::SignatureKey;
+use anyhow::{Context as _, Error, anyhow, bail};
+use openpgp::parse::Parse as _;
+use openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper,
+};
+use openpgp::policy::StandardPolicy;
+use openpgp::{Cert, KeyHandle};
+use sequoia_openpgp as openpgp;
+use std::io::Read as _;
+use std::path::Path;
+
+/// Verify the detached `signature` of the file at `path` with `key`.
+///
+/// This uses blocking IO, and should be called from a blocking task.
+pub(crate) fn verify(path: &Path, signature: &[u8], key: &SignatureKey) -> Result<(), Error> {
+    match key {
+        SignatureKey::Minisign(key) => {
+            let key = minisign_verify::PublicKey::from_base64(key)
+                .map_err(|e| anyhow!("invalid minisign public key: {e}"))?;
+            let signature = std::str::from_utf8(signature)
+                .context("the minisign signature is not valid UTF-8")
+                .and_then(|signature| {
+                    minisign_verify::Signature::decode(signature)
+                        .map_err(|e| anyhow!("invalid minisign signature: {e}"))
+                })?;
+            // Legacy signatures sign the whole file rather than its hash, and can't be verified
+            // without reading the file in memory.
+            let mut verifier = key
+                .verify_stream(&signature)
+                .map_err(|e| anyhow!("unsupported minisign signature: {e}"))?;
+            let mut file = std::fs::File::open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let read = file
+                    .read(&mut buf)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                if read == 0 {
+                    break;
+                }
+                verifier.update(&buf[..read]);
+            }
+            verifier
+                .finalize()
+                .map_err(|e| anyhow!("minisign verification failed: {e}"))
+        }
+        SignatureKey::Pgp(key_file) => {
+            let cert = Cert::from_file(key_file)
+                .with_context(|| format!("failed to read the key {}", key_file.display()))?;
+            let policy = StandardPolicy::new();
+            let mut verifier = DetachedVerifierBuilder::from_bytes(signature)?.with_policy(
+                &policy,
+                None,
+                PgpHelper { cert },
+            )?;
+            verifier.verify_file(path)
+        }
+    }
+}
+
+/// Provides the key to the OpenPGP verifier, and accepts the signature only if it was made by it.
+struct PgpHelper {
+    cert: Cert,
+}
+
+impl VerificationHelper for PgpHelper {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(vec![self.cert.clone()])
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                if results.iter().any(Result::is_ok) {
+                    return Ok(());
+                }
+                bail!("no valid signature made by {}", self.cert.fingerprint());
+            }
+        }
+        bail!("no signature found");
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::{Context as _, Error};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// On-disk cache of the files known to be present on the CDN, so that repeated runs don't check
+/// them again. Entries are keyed by path and only match if the hash is the same.
+pub(crate) struct StatusCache {
+    path: PathBuf,
+    present: HashMap<String, String>,
+}
+
+impl StatusCache {
+    /// Load the cache at `path`. A missing or unreadable cache is treated as empty.
+    pub(crate) fn load(path: &Path) -> Self {
+        let present = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                warn!(
+                    "ignoring the corrupt status cache {}: {err}",
+                    path.display()
+                );
+                HashMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                warn!("ignoring the status cache {}: {err}", path.display());
+                HashMap::new()
+            }
+        };
+        Self {
+            path: path.to_owned(),
+            present,
+        }
+    }
+
+    pub(crate) fn is_present(&self, path: &str, sha256: &str) -> bool {
+        self.present
+            .get(path)
+            .is_some_and(|cached| cached == sha256)
+    }
+
+    pub(crate) fn insert(&mut self, path: String, sha256: String) {
+        self.present.insert(path, sha256);
+    }
+
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        std::fs::write(&self.path, serde_json::to_string(&self.present)?)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
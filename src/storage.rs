@@ -1,77 +1,453 @@
-use anyhow::{Error, bail};
-use aws_sdk_s3::error::SdkError;
+use crate::doctor::Report;
+use crate::http::{self, HttpArgs};
+use crate::utils::{bytes_to_hex, is_sha256_hex};
+use anyhow::{Context as _, Error, anyhow, bail};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::ProvideCredentials as _;
+use aws_sdk_s3::config::Region;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::error::{ProvideErrorMetadata as _, SdkError};
 use aws_sdk_s3::operation::get_object::GetObjectError;
-use aws_sdk_s3::operation::head_object::HeadObjectError;
-use aws_sdk_s3::primitives::ByteStream;
-use reqwest::StatusCode;
+use aws_sdk_s3::operation::head_object::{HeadObjectError, HeadObjectOutput};
+use aws_sdk_s3::primitives::{ByteStream, DateTimeFormat};
+use aws_sdk_s3::types::{MetadataDirective, TaggingDirective};
+use md5::{Digest as _, Md5};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::header::{CONTENT_LENGTH, HeaderName, LAST_MODIFIED};
+use reqwest::{StatusCode, Url};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Characters to escape when a key is part of a URL, either on the CDN or in the
+/// `x-amz-copy-source` header. Keys are otherwise passed unescaped to the SDK.
+const KEY_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Characters to escape in the `x-amz-tagging` header, which is encoded like a query string.
+const TAG_ESCAPE: &AsciiSet = &KEY_ESCAPE.add(b'/');
+
+/// Default number of retries of the S3 requests failing with transient errors.
+pub(crate) const DEFAULT_S3_RETRIES: u32 = 5;
+
+/// Default number of retries of the status checks failing with transient errors.
+pub(crate) const DEFAULT_STATUS_RETRIES: u32 = 3;
+
+/// Delay before the first retry of a status check, doubled after each attempt.
+const STATUS_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+const METADATA_SOURCE: &str = "source-url";
+const METADATA_FILE_NAME: &str = "upstream-filename";
+const METADATA_MD5: &str = "content-md5";
+const METADATA_SHA256: &str = "content-sha256";
 
 pub(crate) enum Storage {
     ReadOnly(CdnReader),
-    ReadWrite(S3Storage),
+    ReadWrite(Box<S3Storage>),
 }
 
 impl Storage {
     pub(crate) async fn file_status(&self, path: &str) -> Result<FileStatus, Error> {
-        if let Some(hash) = self.get_file(&format!("{path}.sha256")).await? {
-            Ok(FileStatus::Present {
-                sha256: hash.trim().to_string(),
-            })
-        } else if self.file_exists(path).await? {
-            Ok(FileStatus::Legacy)
-        } else {
-            Ok(FileStatus::Missing)
+        match self {
+            Storage::ReadOnly(cdn) => Replica::Cdn(cdn).file_status(path).await,
+            Storage::ReadWrite(s3) => {
+                let mut result = None;
+                for bucket in &s3.buckets {
+                    let known = match s3.etag_status {
+                        true => bucket.status_from_etag(path).await?,
+                        false => None,
+                    };
+                    let status = match known {
+                        Some(status) => status,
+                        None => Replica::S3(bucket).file_status(path).await?,
+                    };
+                    result = Some(match (result, status) {
+                        (None, status) => status,
+                        (Some(corrupt @ FileStatus::CorruptSidecar { .. }), _)
+                        | (_, corrupt @ FileStatus::CorruptSidecar { .. }) => corrupt,
+                        (Some(inconsistent @ FileStatus::Inconsistent { .. }), _)
+                        | (_, inconsistent @ FileStatus::Inconsistent { .. }) => inconsistent,
+                        // A file missing from any replica is uploaded again, healing the gap.
+                        (Some(FileStatus::Missing), _) | (_, FileStatus::Missing) => {
+                            FileStatus::Missing
+                        }
+                        (
+                            Some(FileStatus::Present { sha256 }),
+                            FileStatus::Present { sha256: other },
+                        ) if sha256 == other => FileStatus::Present { sha256 },
+                        (Some(FileStatus::Present { .. }), FileStatus::Present { .. }) => {
+                            bail!("the replicas contain different versions of {path}")
+                        }
+                    });
+                }
+                Ok(result.expect("no s3 buckets configured"))
+            }
+        }
+    }
+
+    /// Same as [`Storage::file_status`], but retrying up to `retries` times with an exponential
+    /// backoff when the check fails with a transient error. Missing files are not retried.
+    pub(crate) async fn file_status_with_retries(
+        &self,
+        path: &str,
+        retries: u32,
+    ) -> Result<FileStatus, Error> {
+        let mut delay = STATUS_RETRY_DELAY;
+        for _ in 0..retries {
+            match self.file_status(path).await {
+                Err(err) if is_transient(&err) => {
+                    warn!("failed to check the status of {path}, retrying in {delay:.2?}: {err:#}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
         }
+        self.file_status(path).await
     }
 
-    pub(crate) async fn upload_file(&self, path: &str, file: &Path) -> Result<(), Error> {
+    /// Upload the file to all the buckets missing it. When `overwrite` is set, the file is
+    /// uploaded to all the buckets, replacing the existing objects. The `source` of the file is
+    /// recorded in the metadata of the objects, along with its hashes when the ETags are used to
+    /// check the status of the files. The objects are tagged with `tags` when present.
+    pub(crate) async fn upload_file(
+        &self,
+        path: &str,
+        file: &Path,
+        sha256: &str,
+        source: Option<&Url>,
+        tags: Option<&BTreeMap<String, String>>,
+        overwrite: bool,
+    ) -> Result<(), Error> {
         match self {
-            Storage::ReadOnly(_) => panic!("unsupported in read-only mode"),
+            Storage::ReadOnly(_) => bail!("unsupported in read-only mode"),
             Storage::ReadWrite(s3) => {
-                s3.put_object(path, ByteStream::from_path(file).await?)
-                    .await
+                let targets = s3.targets(path, overwrite).await?;
+                // Objects already present in some buckets, for example after an interrupted
+                // upload, must match the file before its `.sha256` file is written.
+                let existing = s3
+                    .buckets
+                    .iter()
+                    .filter(|bucket| !targets.iter().any(|t| std::ptr::eq(*t, *bucket)))
+                    .collect::<Vec<_>>();
+                let (len, md5) = if s3.verify_after_upload || s3.etag_status || !existing.is_empty()
+                {
+                    let file = file.to_owned();
+                    let (len, md5) = tokio::task::spawn_blocking(move || md5_of(&file)).await??;
+                    (len, Some(md5))
+                } else {
+                    (tokio::fs::metadata(file).await?.len(), None)
+                };
+                for bucket in existing {
+                    bucket.verify_object(path, len, md5.as_deref()).await?;
+                }
+                let mut metadata = source_metadata(source);
+                if s3.etag_status
+                    && let Some(md5) = &md5
+                {
+                    metadata.insert(METADATA_MD5.to_string(), md5.clone());
+                    metadata.insert(METADATA_SHA256.to_string(), sha256.to_string());
+                }
+                let tagging = tags.map(tagging_header);
+                // The `.sha256` file is the marker of a complete upload, and is written by the
+                // caller only once this returns: make sure every object was fully stored first.
+                for bucket in targets {
+                    bucket
+                        .put_object(
+                            path,
+                            ByteStream::from_path(file).await?,
+                            PutOptions {
+                                overwrite,
+                                metadata: Some(metadata.clone()),
+                                tagging: tagging.clone(),
+                            },
+                        )
+                        .await?;
+                    bucket.verify_object(path, len, md5.as_deref()).await?;
+                    if s3.verify_after_upload
+                        && let Some(tags) = tags
+                        && bucket.object_tags(path).await? != *tags
+                    {
+                        bail!(
+                            "the tags of {path} in bucket {} don't match what was uploaded",
+                            bucket.name
+                        );
+                    }
+                }
+                Ok(())
             }
         }
     }
 
-    pub(crate) async fn write_contents(&self, path: &str, content: &[u8]) -> Result<(), Error> {
+    /// Size and last modification date of the object at `path`, read from the CDN or from the
+    /// first bucket, if it exists.
+    pub(crate) async fn object_info(&self, path: &str) -> Result<Option<ObjectInfo>, Error> {
+        Ok(self.head(path).await?.map(|head| head.info))
+    }
+
+    /// Name of the upstream file the object at `path` was mirrored from, as recorded in its
+    /// metadata when uploading it, or taken from its recorded source URL for objects uploaded
+    /// before the name was recorded. Returns `None` for objects without either.
+    pub(crate) async fn upstream_file_name(&self, path: &str) -> Result<Option<String>, Error> {
+        let Some(mut head) = self.head(path).await? else {
+            return Ok(None);
+        };
+        Ok(head.metadata.remove(METADATA_FILE_NAME).or_else(|| {
+            let source = Url::parse(head.metadata.get(METADATA_SOURCE)?).ok()?;
+            source.path().split('/').next_back().map(String::from)
+        }))
+    }
+
+    /// Send a HEAD request for the object at `path` to the CDN or to the first bucket, returning
+    /// `None` if it doesn't exist.
+    async fn head(&self, path: &str) -> Result<Option<ObjectHead>, Error> {
         match self {
-            Storage::ReadOnly(_) => panic!("unsupported in read-only mode"),
+            Storage::ReadOnly(cdn) => {
+                let (url, response) = cdn.head(path).await?;
+                match response.status() {
+                    StatusCode::OK => {}
+                    StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => return Ok(None),
+                    status => return Err(StatusError { status, url }.into()),
+                }
+                let header = |name: HeaderName| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from)
+                };
+                // The CDN exposes the metadata of the objects as headers.
+                let metadata = response
+                    .headers()
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let name = name.as_str().strip_prefix("x-amz-meta-")?;
+                        Some((name.to_string(), value.to_str().ok()?.to_string()))
+                    })
+                    .collect();
+                Ok(Some(ObjectHead {
+                    info: ObjectInfo {
+                        size: header(CONTENT_LENGTH).and_then(|size| size.parse().ok()),
+                        last_modified: header(LAST_MODIFIED),
+                    },
+                    metadata,
+                }))
+            }
             Storage::ReadWrite(s3) => {
-                s3.put_object(path, ByteStream::from(content.to_vec()))
-                    .await
+                let bucket = s3.buckets.first().expect("no s3 buckets configured");
+                Ok(bucket.head_object(path).await?.map(|response| ObjectHead {
+                    info: ObjectInfo {
+                        size: response
+                            .content_length()
+                            .and_then(|size| size.try_into().ok()),
+                        last_modified: response
+                            .last_modified()
+                            .and_then(|date| date.fmt(DateTimeFormat::HttpDate).ok()),
+                    },
+                    metadata: response.metadata().cloned().unwrap_or_default(),
+                }))
             }
         }
     }
 
+    /// Content of the small file at `path`, or `None` if it's missing from any of the replicas so
+    /// that it's written again.
+    pub(crate) async fn read_contents(&self, path: &str) -> Result<Option<String>, Error> {
+        match self {
+            Storage::ReadOnly(cdn) => Replica::Cdn(cdn).get_file(path).await,
+            Storage::ReadWrite(s3) => {
+                let mut result = None;
+                for bucket in &s3.buckets {
+                    let Some(content) = Replica::S3(bucket).get_file(path).await? else {
+                        return Ok(None);
+                    };
+                    if let Some(previous) = &result
+                        && *previous != content
+                    {
+                        bail!("the replicas contain different versions of {path}");
+                    }
+                    result = Some(content);
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    pub(crate) async fn write_contents(
+        &self,
+        path: &str,
+        content: &[u8],
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        match self {
+            Storage::ReadOnly(_) => bail!("unsupported in read-only mode"),
+            Storage::ReadWrite(s3) => {
+                for bucket in s3.targets(path, overwrite).await? {
+                    bucket
+                        .put_object(
+                            path,
+                            ByteStream::from(content.to_vec()),
+                            PutOptions {
+                                overwrite,
+                                ..PutOptions::default()
+                            },
+                        )
+                        .await?;
+                    if s3.verify_after_upload
+                        && Replica::S3(bucket)
+                            .get_file(path)
+                            .await?
+                            .as_deref()
+                            .map(str::as_bytes)
+                            != Some(content)
+                    {
+                        bail!(
+                            "the content of {path} in bucket {} doesn't match what was uploaded",
+                            bucket.name
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy the object at `from` to `to` in all the buckets, without downloading it. Fails if
+    /// `to` already exists in any of the buckets, as the copies are conditional.
+    pub(crate) async fn copy_file(&self, from: &str, to: &str) -> Result<(), Error> {
+        match self {
+            Storage::ReadOnly(_) => bail!("unsupported in read-only mode"),
+            Storage::ReadWrite(s3) => {
+                for bucket in &s3.buckets {
+                    bucket.copy_object(from, to, None, None).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy the object at `from`, which has the same content as the file to upload to `to`, in
+    /// all the buckets instead of uploading the file again. The metadata and tags of the copies
+    /// are set like [`Storage::upload_file`] does, except for the hashes recorded for the ETag
+    /// status checks. Fails if `to` already exists in any of the buckets, as the copies are
+    /// conditional.
+    pub(crate) async fn copy_duplicate(
+        &self,
+        from: &str,
+        to: &str,
+        source: Option<&Url>,
+        tags: Option<&BTreeMap<String, String>>,
+    ) -> Result<(), Error> {
+        match self {
+            Storage::ReadOnly(_) => bail!("unsupported in read-only mode"),
+            Storage::ReadWrite(s3) => {
+                let tagging = tags.map(tagging_header);
+                for bucket in &s3.buckets {
+                    bucket
+                        .copy_object(from, to, Some(source_metadata(source)), tagging.clone())
+                        .await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Delete the object at `path` from all the buckets.
+    pub(crate) async fn delete_file(&self, path: &str) -> Result<(), Error> {
+        match self {
+            Storage::ReadOnly(_) => bail!("unsupported in read-only mode"),
+            Storage::ReadWrite(s3) => {
+                for bucket in &s3.buckets {
+                    bucket.delete_object(path).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A single place where mirrored files are stored.
+enum Replica<'a> {
+    Cdn(&'a CdnReader),
+    S3(&'a S3Bucket),
+}
+
+impl Replica<'_> {
+    async fn file_status(&self, path: &str) -> Result<FileStatus, Error> {
+        if let Some(hash) = self.get_file(&sidecar_key(path)).await? {
+            let hash = hash.trim();
+            if !is_sha256_hex(hash) {
+                Ok(FileStatus::CorruptSidecar {
+                    content: hash.to_string(),
+                })
+            } else if self.file_exists(path).await? {
+                Ok(FileStatus::Present {
+                    sha256: hash.to_string(),
+                })
+            } else {
+                Ok(FileStatus::Inconsistent {
+                    missing: MissingPart::Object {
+                        sha256: hash.to_string(),
+                    },
+                })
+            }
+        } else if self.file_exists(path).await? {
+            Ok(FileStatus::Inconsistent {
+                missing: MissingPart::Sidecar,
+            })
+        } else {
+            Ok(FileStatus::Missing)
+        }
+    }
+
     async fn get_file(&self, path: &str) -> Result<Option<String>, Error> {
         match self {
-            Storage::ReadOnly(storage) => {
-                let url = format!("{}/{}", storage.cdn_url, path.replace("+", "%2B"));
-                let response = storage.http.get(&url).send().await?;
+            Replica::Cdn(storage) => {
+                let url = storage.url(path);
+                let response = storage
+                    .http
+                    .get(&url)
+                    .send()
+                    .await
+                    .map_err(|e| http::map_err(e, &url))?;
                 match response.status() {
-                    StatusCode::OK => Ok(Some(response.text().await?)),
+                    StatusCode::OK => Ok(Some(
+                        response.text().await.map_err(|e| http::map_err(e, &url))?,
+                    )),
                     StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(None),
-                    status => bail!("unexpected status {status} when requesting {url}"),
+                    status => Err(StatusError { status, url }.into()),
                 }
             }
-            Storage::ReadWrite(storage) => {
+            Replica::S3(storage) => {
+                let start = Instant::now();
                 let response = storage
-                    .s3
+                    .client
                     .get_object()
-                    .bucket(&storage.s3_bucket)
+                    .bucket(&storage.name)
                     .key(path)
                     .send()
                     .await;
+                debug!(
+                    "GetObject {path} on bucket {} took {:.2?}",
+                    storage.name,
+                    start.elapsed()
+                );
                 match response {
                     Ok(success) => Ok(Some(String::from_utf8(
                         success.body.collect().await?.to_vec(),
                     )?)),
                     Err(error) => {
-                        if let SdkError::ServiceError(service) = &error {
-                            if let GetObjectError::NoSuchKey(_) = service.err() {
-                                return Ok(None);
-                            }
+                        if let SdkError::ServiceError(service) = &error
+                            && let GetObjectError::NoSuchKey(_) = service.err()
+                        {
+                            return Ok(None);
                         }
                         Err(error.into())
                     }
@@ -82,35 +458,15 @@ impl Storage {
 
     async fn file_exists(&self, path: &str) -> Result<bool, Error> {
         match self {
-            Storage::ReadOnly(storage) => {
-                let url = format!("{}/{path}", storage.cdn_url);
-                let response = storage.http.head(&url).send().await?;
+            Replica::Cdn(storage) => {
+                let (url, response) = storage.head(path).await?;
                 match response.status() {
                     StatusCode::OK => Ok(true),
                     StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(false),
-                    status => bail!("unexpected status {status} when requesting {url}"),
-                }
-            }
-            Storage::ReadWrite(storage) => {
-                let response = storage
-                    .s3
-                    .head_object()
-                    .bucket(&storage.s3_bucket)
-                    .key(path)
-                    .send()
-                    .await;
-                match response {
-                    Ok(_) => Ok(true),
-                    Err(error) => {
-                        if let SdkError::ServiceError(service) = &error {
-                            if let HeadObjectError::NotFound(_) = service.err() {
-                                return Ok(false);
-                            }
-                        }
-                        Err(error.into())
-                    }
+                    status => Err(StatusError { status, url }.into()),
                 }
             }
+            Replica::S3(storage) => Ok(storage.head_object(path).await?.is_some()),
         }
     }
 }
@@ -121,46 +477,938 @@ pub(crate) struct CdnReader {
 }
 
 impl CdnReader {
-    pub(crate) fn new(cdn_url: String) -> Self {
-        Self {
-            http: reqwest::Client::new(),
+    pub(crate) fn new(cdn_url: String, http: &HttpArgs) -> Result<Self, Error> {
+        Ok(Self {
+            http: http.client()?,
             cdn_url,
+        })
+    }
+
+    /// URL of the file at `path` on the CDN. Characters like `+`, spaces or `#` are escaped, as
+    /// they would otherwise change the meaning of the URL.
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.cdn_url, utf8_percent_encode(path, KEY_ESCAPE))
+    }
+
+    /// Status of a HEAD request to the file at `path` on the CDN.
+    pub(crate) async fn head_status(&self, path: &str) -> Result<StatusCode, Error> {
+        Ok(self.head(path).await?.1.status())
+    }
+
+    /// Send a HEAD request for the file at `path` on the CDN, returning its URL along with the
+    /// response.
+    async fn head(&self, path: &str) -> Result<(String, reqwest::Response), Error> {
+        let url = self.url(path);
+        let response = self
+            .http
+            .head(&url)
+            .send()
+            .await
+            .map_err(|e| http::map_err(e, &url))?;
+        Ok((url, response))
+    }
+
+    /// Read the text file at `path` from the CDN, if it exists.
+    pub(crate) async fn get_text(&self, path: &str) -> Result<Option<String>, Error> {
+        Replica::Cdn(self).get_file(path).await
+    }
+
+    /// Run the checks of the `doctor` command against the CDN.
+    pub(crate) async fn doctor(&self, report: &mut Report) {
+        let result = async {
+            let response = self
+                .http
+                .head(&self.cdn_url)
+                .send()
+                .await
+                .map_err(|e| http::map_err(e, &self.cdn_url))?;
+            // The root of the CDN doesn't necessarily exist, we only care about reaching it.
+            if response.status().is_server_error() {
+                bail!("unexpected status {}", response.status());
+            }
+            Ok(())
         }
+        .await;
+        report.check(&format!("reach the CDN at {}", self.cdn_url), true, result);
     }
 }
 
 pub(crate) struct S3Storage {
-    s3: aws_sdk_s3::Client,
-    s3_bucket: String,
+    config: SdkConfig,
+    buckets: Vec<S3Bucket>,
+    verify_after_upload: bool,
+    etag_status: bool,
 }
 
 impl S3Storage {
-    pub(crate) async fn new(s3_bucket: String) -> Result<Self, Error> {
+    /// Each bucket is either `name` or `name:region`. When the region is not given and S3 reports
+    /// that the bucket is stored outside of the region configured in the environment, its actual
+    /// region is used instead.
+    ///
+    /// When `endpoint_url` is set, the requests are sent to that S3-compatible service instead of
+    /// AWS, using path-style addressing.
+    ///
+    /// Requests failing with transient errors (such as throttling) are retried up to `retries`
+    /// times, with the adaptive retry mode of the SDK slowing down the requests when throttled.
+    ///
+    /// With `verify_after_upload`, the objects are read back after being uploaded, to check that
+    /// they were stored correctly.
+    ///
+    /// With `etag_status`, the hashes of the uploaded objects are recorded in their metadata, and
+    /// the status of the files is determined from a single HEAD request when the ETag of the
+    /// object matches the recorded MD5 hash, without reading the `.sha256` file.
+    pub(crate) async fn new(
+        buckets: &[String],
+        endpoint_url: Option<&str>,
+        retries: u32,
+        verify_after_upload: bool,
+        etag_status: bool,
+    ) -> Result<Self, Error> {
+        if buckets.is_empty() {
+            bail!("at least one s3 bucket must be configured");
+        }
         let config = aws_config::load_from_env().await;
+        let client = |region: Option<&str>| {
+            let mut builder = aws_sdk_s3::config::Builder::from(&config)
+                .retry_config(RetryConfig::adaptive().with_max_attempts(retries + 1));
+            if let Some(region) = region {
+                builder = builder.region(Region::new(region.to_string()));
+            }
+            if let Some(endpoint_url) = endpoint_url {
+                builder = builder.endpoint_url(endpoint_url).force_path_style(true);
+            }
+            aws_sdk_s3::Client::from_conf(builder.build())
+        };
+
+        let mut s3_buckets = Vec::new();
+        for bucket in buckets {
+            let (name, region) = match bucket.split_once(':') {
+                Some((name, region)) => (name, Some(region)),
+                None => (bucket.as_str(), None),
+            };
+            let mut bucket = S3Bucket {
+                client: client(region),
+                name: name.to_string(),
+            };
+            if region.is_none()
+                && endpoint_url.is_none()
+                && let Some(actual) = bucket.redirected_region().await
+            {
+                info!(
+                    "bucket {name} is stored in region {actual}, using it instead of {} \
+                     (pass `--s3-bucket {name}:{actual}` to skip this check)",
+                    config
+                        .region()
+                        .map_or("the default region".to_string(), ToString::to_string)
+                );
+                bucket.client = client(Some(&actual));
+            }
+            s3_buckets.push(bucket);
+        }
+
         Ok(S3Storage {
-            s3: aws_sdk_s3::Client::new(&config),
-            s3_bucket,
+            buckets: s3_buckets,
+            config,
+            verify_after_upload,
+            etag_status,
         })
     }
 
-    async fn put_object(&self, key: &str, body: ByteStream) -> Result<(), Error> {
-        self.s3
+    /// Buckets the object at `path` should be written to: the ones in which it doesn't exist
+    /// yet, or all of them when overwriting.
+    async fn targets(&self, path: &str, overwrite: bool) -> Result<Vec<&S3Bucket>, Error> {
+        let mut targets = Vec::new();
+        for bucket in &self.buckets {
+            if overwrite || !Replica::S3(bucket).file_exists(path).await? {
+                targets.push(bucket);
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Check that the credentials can upload to all the buckets, by writing a scratch object to
+    /// each of them. The scratch objects are deleted afterwards when the policy allows it.
+    pub(crate) async fn check_write_access(&self) -> Result<(), Error> {
+        let scratch_key = scratch_key("ci-mirrors-check");
+        for bucket in &self.buckets {
+            bucket
+                .put_object(
+                    &scratch_key,
+                    ByteStream::from_static(b"ci-mirrors credentials check\n"),
+                    PutOptions::default(),
+                )
+                .await
+                .with_context(|| format!("cannot upload to bucket {}", bucket.name))?;
+            if let Err(err) = bucket.delete_object(&scratch_key).await {
+                warn!(
+                    "failed to delete {scratch_key} from bucket {}: {err:?}",
+                    bucket.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the checks of the `doctor` command against all the buckets. `known_key` must be the
+    /// path of a file already present in the buckets.
+    pub(crate) async fn doctor(&self, known_key: &str, report: &mut Report) {
+        let result = async {
+            match self.config.credentials_provider() {
+                Some(provider) => Ok(provider.provide_credentials().await.map(|_| ())?),
+                None => bail!("no credentials provider configured"),
+            }
+        }
+        .await;
+        report.check("load AWS credentials", true, result);
+
+        let scratch_key = scratch_key("ci-mirrors-doctor");
+        for bucket in &self.buckets {
+            let result = match Replica::S3(bucket).file_exists(known_key).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(anyhow!("{known_key} doesn't exist")),
+                Err(err) => Err(err),
+            };
+            report.check(
+                &format!("read {known_key} from bucket {}", bucket.name),
+                true,
+                result,
+            );
+
+            let result = bucket
+                .put_object(
+                    &scratch_key,
+                    ByteStream::from_static(b"ci-mirrors doctor\n"),
+                    PutOptions::default(),
+                )
+                .await;
+            let uploaded = result.is_ok();
+            report.check(
+                &format!("write {scratch_key} to bucket {}", bucket.name),
+                true,
+                result,
+            );
+
+            // Deleting is not needed by the tool itself, so the policy might forbid it.
+            if uploaded {
+                let result = bucket.delete_object(&scratch_key).await;
+                report.check(
+                    &format!("delete {scratch_key} from bucket {}", bucket.name),
+                    false,
+                    result,
+                );
+            }
+        }
+    }
+}
+
+struct S3Bucket {
+    client: aws_sdk_s3::Client,
+    name: String,
+}
+
+/// Options of [`S3Bucket::put_object`].
+#[derive(Default)]
+struct PutOptions {
+    /// Replace the object if it already exists, instead of failing.
+    overwrite: bool,
+    metadata: Option<HashMap<String, String>>,
+    /// Tags of the object, encoded with [`tagging_header`].
+    tagging: Option<String>,
+}
+
+impl S3Bucket {
+    async fn put_object(
+        &self,
+        key: &str,
+        body: ByteStream,
+        options: PutOptions,
+    ) -> Result<(), Error> {
+        let start = Instant::now();
+        let PutOptions {
+            overwrite,
+            metadata,
+            tagging,
+        } = options;
+        let request_tagged = tagging.is_some();
+        let mut request = self
+            .client
             .put_object()
-            .bucket(&self.s3_bucket)
+            .bucket(&self.name)
             .key(key)
             .body(body)
+            .set_metadata(metadata)
+            .set_tagging(tagging);
+        if !overwrite {
             // Prevent overriding an existing file. Note that the IAM policy used to upload
             // objects in CI *enforces* the present of this line. If you remove it without
             // first changing the policy, the request will fail.
-            .if_none_match("*")
+            request = request.if_none_match("*");
+        }
+        if let Err(error) = request.send().await {
+            let code = match &error {
+                SdkError::ServiceError(service) => service.err().code(),
+                _ => None,
+            };
+            let context = match code {
+                Some("AccessDenied") if overwrite => format!(
+                    "overwriting {key} in bucket {} was denied, the IAM policy must be changed \
+                     to allow uploads without the If-None-Match header",
+                    self.name
+                ),
+                Some("AccessDenied") if request_tagged => format!(
+                    "uploading {key} to bucket {} was denied, the credentials lack the \
+                     s3:PutObject or s3:PutObjectTagging permission",
+                    self.name
+                ),
+                Some("AccessDenied") => format!(
+                    "uploading {key} to bucket {} was denied, the credentials lack the \
+                     s3:PutObject permission",
+                    self.name
+                ),
+                // Returned when the If-None-Match condition fails.
+                Some("PreconditionFailed") => format!(
+                    "{key} already exists in bucket {}, use --allow-overwrite to replace it",
+                    self.name
+                ),
+                _ => return Err(error.into()),
+            };
+            return Err(Error::from(error).context(context));
+        }
+        debug!(
+            "PutObject {key} on bucket {} took {:.2?}",
+            self.name,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Tags of the object at `key`.
+    async fn object_tags(&self, key: &str) -> Result<BTreeMap<String, String>, Error> {
+        let response = self
+            .client
+            .get_object_tagging()
+            .bucket(&self.name)
+            .key(key)
+            .send()
+            .await?;
+        Ok(response
+            .tag_set()
+            .iter()
+            .map(|tag| (tag.key().to_string(), tag.value().to_string()))
+            .collect())
+    }
+
+    /// Send a HEAD request for the object at `key`, returning `None` if it doesn't exist.
+    async fn head_object(&self, key: &str) -> Result<Option<HeadObjectOutput>, Error> {
+        let start = Instant::now();
+        let response = self
+            .client
+            .head_object()
+            .bucket(&self.name)
+            .key(key)
+            .send()
+            .await;
+        debug!(
+            "HeadObject {key} on bucket {} took {:.2?}",
+            self.name,
+            start.elapsed()
+        );
+        match response {
+            Ok(response) => Ok(Some(response)),
+            Err(SdkError::ServiceError(service))
+                if matches!(service.err(), HeadObjectError::NotFound(_)) =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Status of the object at `key` according to the hashes recorded in its metadata, or `None`
+    /// when it can't be determined this way: when the object doesn't exist, was uploaded without
+    /// the hashes, its ETag is not the MD5 of its content (for multipart uploads), or its sidecar
+    /// is missing.
+    async fn status_from_etag(&self, key: &str) -> Result<Option<FileStatus>, Error> {
+        let Some(response) = self.head_object(key).await? else {
+            return Ok(None);
+        };
+
+        let etag = response.e_tag().unwrap_or_default().trim_matches('"');
+        let metadata = response.metadata();
+        let recorded = |name: &str| metadata.and_then(|m| m.get(name)).map(String::as_str);
+        match (recorded(METADATA_MD5), recorded(METADATA_SHA256)) {
+            (Some(md5), Some(sha256)) if md5 == etag && is_sha256_hex(sha256) => {
+                // The sidecar is written last, so an object without it is an interrupted upload,
+                // which the full check reports as inconsistent.
+                if !Replica::S3(self).file_exists(&sidecar_key(key)).await? {
+                    return Ok(None);
+                }
+                Ok(Some(FileStatus::Present {
+                    sha256: sha256.to_string(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Check that the object at `key` has the expected size and, if passed, MD5 hash. This relies
+    /// on the ETag of objects uploaded in a single request being the MD5 hash of their content.
+    async fn verify_object(&self, key: &str, len: u64, md5: Option<&str>) -> Result<(), Error> {
+        let Some(response) = self.head_object(key).await? else {
+            bail!("{key} is missing from bucket {}", self.name);
+        };
+
+        let stored_len = response.content_length().unwrap_or_default();
+        if u64::try_from(stored_len).ok() != Some(len) {
+            bail!(
+                "{key} in bucket {} has size {stored_len}, but {len} bytes were uploaded",
+                self.name
+            );
+        }
+        let etag = response.e_tag().unwrap_or_default().trim_matches('"');
+        if let Some(md5) = md5
+            && etag != md5
+        {
+            bail!(
+                "{key} in bucket {} has ETag {etag}, but the MD5 of the uploaded file is {md5}",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Copy the object at `from` to `to`. When `metadata` is set, the metadata and the tags of
+    /// the copy are replaced with `metadata` and `tagging` instead of being copied from `from`.
+    async fn copy_object(
+        &self,
+        from: &str,
+        to: &str,
+        metadata: Option<HashMap<String, String>>,
+        tagging: Option<String>,
+    ) -> Result<(), Error> {
+        let start = Instant::now();
+        let mut request = self
+            .client
+            .copy_object()
+            .copy_source(format!(
+                "{}/{}",
+                self.name,
+                utf8_percent_encode(from, KEY_ESCAPE)
+            ))
+            .bucket(&self.name)
+            .key(to)
+            // Like the uploads, copies must never replace an existing object, and the IAM policy
+            // enforces this header.
+            .if_none_match("*");
+        if metadata.is_some() {
+            request = request
+                .metadata_directive(MetadataDirective::Replace)
+                .set_metadata(metadata)
+                .tagging_directive(TaggingDirective::Replace)
+                .set_tagging(tagging);
+        }
+        if let Err(error) = request.send().await {
+            let code = match &error {
+                SdkError::ServiceError(service) => service.err().code(),
+                _ => None,
+            };
+            let context = match code {
+                Some("PreconditionFailed") => {
+                    format!("{to} already exists in bucket {}", self.name)
+                }
+                _ => format!("failed to copy {from} to {to} in bucket {}", self.name),
+            };
+            return Err(Error::from(error).context(context));
+        }
+        debug!(
+            "CopyObject {from} to {to} on bucket {} took {:.2?}",
+            self.name,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Region the bucket is stored in, when S3 redirects the requests because it's not the
+    /// region of the client.
+    async fn redirected_region(&self) -> Option<String> {
+        let error = self
+            .client
+            .head_bucket()
+            .bucket(&self.name)
+            .send()
+            .await
+            .err()?;
+        let SdkError::ServiceError(service) = &error else {
+            return None;
+        };
+        if service.raw().status().as_u16() != 301 {
+            return None;
+        }
+        service
+            .raw()
+            .headers()
+            .get("x-amz-bucket-region")
+            .map(str::to_string)
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.name)
+            .key(key)
             .send()
             .await?;
         Ok(())
     }
 }
 
+/// Error returned when the CDN responds with a status other than success or not found.
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+    url: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unexpected status {} when requesting {}",
+            self.status, self.url
+        )
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Whether the error is likely to go away when retrying, like timeouts, connection failures,
+/// server errors and throttling.
+fn is_transient(err: &Error) -> bool {
+    fn is_transient_status(status: u16) -> bool {
+        status == 429 || (500..=599).contains(&status)
+    }
+    fn is_transient_sdk<E>(err: &SdkError<E, HttpResponse>) -> bool {
+        match err {
+            SdkError::TimeoutError(_)
+            | SdkError::DispatchFailure(_)
+            | SdkError::ResponseError(_) => true,
+            SdkError::ServiceError(service) => is_transient_status(service.raw().status().as_u16()),
+            _ => false,
+        }
+    }
+
+    err.chain().any(|cause| {
+        if cause.is::<http::TimeoutError>() {
+            true
+        } else if let Some(err) = cause.downcast_ref::<StatusError>() {
+            is_transient_status(err.status.as_u16())
+        } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            err.is_timeout() || err.is_connect()
+        } else if let Some(err) = cause.downcast_ref::<SdkError<GetObjectError, HttpResponse>>() {
+            is_transient_sdk(err)
+        } else if let Some(err) = cause.downcast_ref::<SdkError<HeadObjectError, HttpResponse>>() {
+            is_transient_sdk(err)
+        } else {
+            false
+        }
+    })
+}
+
+/// Response to a HEAD request of an object.
+struct ObjectHead {
+    info: ObjectInfo,
+    /// Metadata recorded when uploading the object.
+    metadata: HashMap<String, String>,
+}
+
+/// Details of an uploaded object, shown when it doesn't match the manifests.
+pub(crate) struct ObjectInfo {
+    pub(crate) size: Option<u64>,
+    pub(crate) last_modified: Option<String>,
+}
+
+impl fmt::Display for ObjectInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{size} bytes")?,
+            None => f.write_str("unknown size")?,
+        }
+        match &self.last_modified {
+            Some(last_modified) => write!(f, ", last modified {last_modified}"),
+            None => Ok(()),
+        }
+    }
+}
+
 pub(crate) enum FileStatus {
     Missing,
-    Legacy,
-    Present { sha256: String },
+    Present {
+        sha256: String,
+    },
+    /// The `.sha256` file exists, but doesn't contain a valid hash.
+    CorruptSidecar {
+        content: String,
+    },
+    /// Only one of the object and its `.sha256` file exists, either because an upload was
+    /// interrupted or because the object was uploaded without this tool.
+    Inconsistent {
+        missing: MissingPart,
+    },
+}
+
+pub(crate) enum MissingPart {
+    /// The object is missing, while the `.sha256` file contains this hash.
+    Object {
+        sha256: String,
+    },
+    Sidecar,
+}
+
+/// Unique key under `directory` to write temporary objects to.
+fn scratch_key(directory: &str) -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{directory}/{millis}")
+}
+
+/// Normalize the prefix of the keys, so that it never starts or ends with a slash and is never
+/// empty.
+pub(crate) fn normalize_prefix(prefix: Option<String>) -> Option<String> {
+    prefix
+        .map(|prefix| prefix.trim_matches('/').to_string())
+        .filter(|prefix| !prefix.is_empty())
+}
+
+/// Key of the object storing the mirrored file `name`, under the normalized `prefix` if any.
+pub(crate) fn object_key(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{prefix}/{name}"),
+        None => name.to_string(),
+    }
+}
+
+/// Suffixes of the files uploaded next to some objects, depending on the flags of the upload and
+/// on their entry.
+pub(crate) const OPTIONAL_SIDECARS: &[&str] = &[".sri", ".sha512", ".members.json"];
+
+/// Key of the `.sha256` file of the object at `key`, whose presence marks it as uploaded.
+pub(crate) fn sidecar_key(key: &str) -> String {
+    format!("{key}.sha256")
+}
+
+/// Encode `tags` for the `x-amz-tagging` header.
+fn tagging_header(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(key, TAG_ESCAPE),
+                utf8_percent_encode(value, TAG_ESCAPE)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Metadata recording where an object was mirrored from, so that the buckets can be inspected
+/// without the manifests.
+fn source_metadata(source: Option<&Url>) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    if let Some(source) = source {
+        metadata.insert(METADATA_SOURCE.to_string(), source.to_string());
+        if let Some(file_name) = source.path().split('/').next_back() {
+            metadata.insert(METADATA_FILE_NAME.to_string(), file_name.to_string());
+        }
+    }
+    metadata
+}
+
+/// Returns the size and the MD5 hash of the file. This uses blocking IO.
+fn md5_of(path: &Path) -> Result<(u64, String), Error> {
+    let mut md5 = Md5::new();
+    let len = std::io::copy(&mut std::fs::File::open(path)?, &mut md5)?;
+    Ok((len, bytes_to_hex(&md5.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{HELLO_SHA256, MockServer, s3_client, sdk_config};
+
+    fn cdn(server: &MockServer) -> Storage {
+        let cdn_url = server.url("").trim_end_matches('/').to_string();
+        Storage::ReadOnly(CdnReader::new(cdn_url, &HttpArgs::default()).unwrap())
+    }
+
+    fn bucket(server: &MockServer) -> S3Bucket {
+        S3Bucket {
+            client: s3_client(server),
+            name: "bucket".into(),
+        }
+    }
+
+    fn tags() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("managed".to_string(), "true".to_string()),
+            ("tool".to_string(), "binutils".to_string()),
+        ])
+    }
+
+    const NO_SUCH_KEY: &[u8] = b"<Error><Code>NoSuchKey</Code></Error>";
+
+    #[tokio::test]
+    async fn sidecar_without_object() {
+        let server =
+            MockServer::with_routes(&[("/foo.tar.gz.sha256", 200, HELLO_SHA256.as_bytes())]);
+        let status = cdn(&server).file_status("foo.tar.gz").await.unwrap();
+        assert!(matches!(
+            status,
+            FileStatus::Inconsistent { missing: MissingPart::Object { sha256 } } if sha256 == HELLO_SHA256
+        ));
+
+        let server =
+            MockServer::with_routes(&[("/bucket/foo.tar.gz.sha256", 200, HELLO_SHA256.as_bytes())]);
+        let status = Replica::S3(&bucket(&server))
+            .file_status("foo.tar.gz")
+            .await
+            .unwrap();
+        assert!(matches!(
+            status,
+            FileStatus::Inconsistent { missing: MissingPart::Object { sha256 } } if sha256 == HELLO_SHA256
+        ));
+    }
+
+    #[tokio::test]
+    async fn object_without_sidecar() {
+        let server = MockServer::with_routes(&[("/foo.tar.gz", 200, b"hello")]);
+        let status = cdn(&server).file_status("foo.tar.gz").await.unwrap();
+        assert!(matches!(
+            status,
+            FileStatus::Inconsistent {
+                missing: MissingPart::Sidecar
+            }
+        ));
+
+        let server = MockServer::with_routes(&[
+            ("/bucket/foo.tar.gz", 200, b"hello"),
+            ("/bucket/foo.tar.gz.sha256", 404, NO_SUCH_KEY),
+        ]);
+        let status = Replica::S3(&bucket(&server))
+            .file_status("foo.tar.gz")
+            .await
+            .unwrap();
+        assert!(matches!(
+            status,
+            FileStatus::Inconsistent {
+                missing: MissingPart::Sidecar
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupt_sidecars() {
+        let short = &HELLO_SHA256[..63];
+        let non_hex = HELLO_SHA256.replace('2', "g");
+        for content in ["", "\n", short, &non_hex] {
+            let server = MockServer::with_routes(&[
+                ("/foo.tar.gz", 200, b"hello"),
+                ("/foo.tar.gz.sha256", 200, content.as_bytes()),
+            ]);
+            let status = cdn(&server).file_status("foo.tar.gz").await.unwrap();
+            assert!(
+                matches!(&status, FileStatus::CorruptSidecar { content: found } if found == content.trim()),
+                "{content:?}"
+            );
+        }
+
+        // Surrounding whitespace is tolerated.
+        let content = format!("{HELLO_SHA256}\n");
+        let server = MockServer::with_routes(&[
+            ("/foo.tar.gz", 200, b"hello"),
+            ("/foo.tar.gz.sha256", 200, content.as_bytes()),
+        ]);
+        let status = cdn(&server).file_status("foo.tar.gz").await.unwrap();
+        assert!(matches!(status, FileStatus::Present { sha256 } if sha256 == HELLO_SHA256));
+    }
+
+    #[tokio::test]
+    async fn names_with_special_characters() {
+        let name = "dir/a+b c#1.tar.gz";
+        let escaped = "dir/a%2Bb%20c%231.tar.gz";
+
+        let server = MockServer::with_routes(&[
+            (&format!("/{escaped}"), 200, b"hello"),
+            (&format!("/{escaped}.sha256"), 200, HELLO_SHA256.as_bytes()),
+        ]);
+        let status = cdn(&server).file_status(name).await.unwrap();
+        assert!(matches!(status, FileStatus::Present { sha256 } if sha256 == HELLO_SHA256));
+
+        let server = MockServer::with_routes(&[
+            (&format!("/bucket/{escaped}"), 200, b"hello"),
+            (
+                &format!("/bucket/{escaped}.sha256"),
+                200,
+                HELLO_SHA256.as_bytes(),
+            ),
+        ]);
+        let status = s3(&server).file_status(name).await.unwrap();
+        assert!(matches!(status, FileStatus::Present { sha256 } if sha256 == HELLO_SHA256));
+    }
+
+    #[tokio::test]
+    async fn put_object_with_tags() {
+        let server = MockServer::start(200, b"");
+        let options = PutOptions {
+            tagging: Some(tagging_header(&tags())),
+            ..PutOptions::default()
+        };
+        bucket(&server)
+            .put_object(
+                "binutils/file.tar.gz",
+                ByteStream::from_static(b"hello"),
+                options,
+            )
+            .await
+            .unwrap();
+        let request = server.requests().remove(0).to_lowercase();
+        assert!(request.starts_with("put /bucket/binutils/file.tar.gz"));
+        assert!(request.contains("\r\nx-amz-tagging: managed=true&tool=binutils\r\n"));
+        assert!(request.contains("\r\nif-none-match: *\r\n"));
+    }
+
+    /// Error of a PutObject request answered by S3 with `status` and the error `code`.
+    async fn put_error(status: u16, code: &str, options: PutOptions) -> String {
+        let body = format!("<Error><Code>{code}</Code><Message>mock</Message></Error>");
+        let server = MockServer::start(status, body.as_bytes());
+        let error = bucket(&server)
+            .put_object("file.tar.gz", ByteStream::from_static(b"hello"), options)
+            .await
+            .unwrap_err();
+        error.to_string()
+    }
+
+    #[tokio::test]
+    async fn put_object_errors() {
+        let error = put_error(412, "PreconditionFailed", PutOptions::default()).await;
+        assert_eq!(
+            error,
+            "file.tar.gz already exists in bucket bucket, use --allow-overwrite to replace it"
+        );
+
+        let error = put_error(403, "AccessDenied", PutOptions::default()).await;
+        assert!(error.ends_with("the credentials lack the s3:PutObject permission"));
+
+        let options = PutOptions {
+            tagging: Some(tagging_header(&tags())),
+            ..PutOptions::default()
+        };
+        let error = put_error(403, "AccessDenied", options).await;
+        assert!(error.ends_with("lack the s3:PutObject or s3:PutObjectTagging permission"));
+
+        let options = PutOptions {
+            overwrite: true,
+            ..PutOptions::default()
+        };
+        let error = put_error(403, "AccessDenied", options).await;
+        assert!(error.contains("allow uploads without the If-None-Match header"));
+
+        // Other errors are returned as is.
+        let error = put_error(400, "InvalidArgument", PutOptions::default()).await;
+        assert!(!error.contains("bucket bucket"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn get_object_tagging() {
+        let server = MockServer::start(
+            200,
+            b"<Tagging><TagSet>\
+              <Tag><Key>managed</Key><Value>true</Value></Tag>\
+              <Tag><Key>tool</Key><Value>binutils</Value></Tag>\
+              </TagSet></Tagging>",
+        );
+        let tags = bucket(&server)
+            .object_tags("binutils/file.tar.gz")
+            .await
+            .unwrap();
+        assert_eq!(tags, self::tags());
+        assert!(server.requests()[0].starts_with("GET /bucket/binutils/file.tar.gz?tagging"));
+    }
+
+    #[tokio::test]
+    async fn upstream_file_name_from_source() {
+        let server = MockServer::with_headers(
+            200,
+            &[(
+                "x-amz-meta-source-url",
+                "https://example.com/dist/foo-1.0.tar.gz",
+            )],
+            b"",
+        );
+        let name = cdn(&server).upstream_file_name("foo.tar.gz").await.unwrap();
+        assert_eq!(name.as_deref(), Some("foo-1.0.tar.gz"));
+    }
+
+    fn s3(server: &MockServer) -> Storage {
+        Storage::ReadWrite(Box::new(S3Storage {
+            config: sdk_config(),
+            buckets: vec![bucket(server)],
+            verify_after_upload: false,
+            etag_status: false,
+        }))
+    }
+
+    #[tokio::test]
+    async fn source_metadata_roundtrip() {
+        let source = Url::parse("https://example.com/dist/foo-1.0.tar.gz").unwrap();
+        let server = MockServer::start(200, b"");
+        let options = PutOptions {
+            metadata: Some(source_metadata(Some(&source))),
+            ..PutOptions::default()
+        };
+        bucket(&server)
+            .put_object("foo.tar.gz", ByteStream::from_static(b"hello"), options)
+            .await
+            .unwrap();
+        let request = server.requests().remove(0).to_lowercase();
+        assert!(
+            request
+                .contains("\r\nx-amz-meta-source-url: https://example.com/dist/foo-1.0.tar.gz\r\n")
+        );
+        assert!(request.contains("\r\nx-amz-meta-upstream-filename: foo-1.0.tar.gz\r\n"));
+
+        // HeadObject returns the metadata the same way it was sent.
+        let server = MockServer::with_headers(
+            200,
+            &[
+                ("x-amz-meta-source-url", source.as_str()),
+                ("x-amz-meta-upstream-filename", "foo-1.0.tar.gz"),
+            ],
+            b"",
+        );
+        let storage = s3(&server);
+        let name = storage.upstream_file_name("foo.tar.gz").await.unwrap();
+        assert_eq!(name.as_deref(), Some("foo-1.0.tar.gz"));
+        let metadata = storage.head("foo.tar.gz").await.unwrap().unwrap().metadata;
+        assert_eq!(metadata, source_metadata(Some(&source)));
+        assert!(server.requests()[0].starts_with("HEAD /bucket/foo.tar.gz "));
+    }
+
+    #[tokio::test]
+    async fn upstream_file_name_recorded() {
+        let server = MockServer::with_headers(
+            200,
+            &[
+                ("x-amz-meta-upstream-filename", "bar.tar.gz"),
+                (
+                    "x-amz-meta-source-url",
+                    "https://example.com/dist/foo-1.0.tar.gz",
+                ),
+            ],
+            b"",
+        );
+        let name = cdn(&server).upstream_file_name("foo.tar.gz").await.unwrap();
+        assert_eq!(name.as_deref(), Some("bar.tar.gz"));
+    }
 }
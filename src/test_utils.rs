@@ -0,0 +1,147 @@
+use crate::manifest::{MirrorFile, Source};
+use aws_config::SdkConfig;
+use aws_credential_types::Credentials;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_sdk_s3::config::{BehaviorVersion, Region};
+use reqwest::Url;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead as _, BufReader, Read as _, Write as _};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// SHA-256 of `hello`.
+pub(crate) const HELLO_SHA256: &str =
+    "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+/// Minimal HTTP server answering the requests with canned responses, and recording the head of
+/// the requests it received.
+pub(crate) struct MockServer {
+    addr: std::net::SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    pub(crate) fn start(status: u16, body: &[u8]) -> Self {
+        Self::with_headers(status, &[], body)
+    }
+
+    pub(crate) fn with_headers(status: u16, headers: &[(&str, &str)], body: &[u8]) -> Self {
+        let response = response(status, headers, body);
+        Self::serve(move |_| response.clone())
+    }
+
+    /// Server answering the requests to each path with the status and body of its route, and
+    /// the requests to the other paths with a 404. Paths are matched without the query string.
+    pub(crate) fn with_routes(routes: &[(&str, u16, &[u8])]) -> Self {
+        let routes: HashMap<String, Vec<u8>> = routes
+            .iter()
+            .map(|(path, status, body)| (path.to_string(), response(*status, &[], body)))
+            .collect();
+        let not_found = response(404, &[], b"");
+        Self::serve(move |path| routes.get(path).unwrap_or(&not_found).clone())
+    }
+
+    fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                    request.push_str(&line);
+                }
+                let mut request_body = vec![0; content_length];
+                let _ = reader.read_exact(&mut request_body);
+
+                let target = request.split(' ').nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+                let mut response = respond(path);
+                if request.starts_with("HEAD ") {
+                    let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                    response.truncate(head_len + 4);
+                }
+                let _ = stream.write_all(&response);
+                recorded.lock().unwrap().push(request);
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+
+    /// Heads of the requests received so far, with one `Name: value\r\n` line per header after
+    /// the request line.
+    pub(crate) fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Raw HTTP response with the `status`, `headers` and `body`.
+fn response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Enabled file named `name`, downloaded from `url` and expected to have the `sha256` hash.
+pub(crate) fn mirror_file(name: &str, sha256: &str, url: &str) -> MirrorFile {
+    MirrorFile {
+        name: name.into(),
+        sha256: sha256.into(),
+        sha512: None,
+        source: Source::Url(Url::parse(url).unwrap()),
+        tags: BTreeMap::new(),
+        license: Some("MIT".into()),
+        rename_from: None,
+        decompress: None,
+        format: None,
+        signature: None,
+        enabled: true,
+    }
+}
+
+/// AWS configuration with static credentials, for the S3 requests sent to a [`MockServer`].
+pub(crate) fn sdk_config() -> SdkConfig {
+    SdkConfig::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+            "key", "secret", None, None, "test",
+        )))
+        .build()
+}
+
+/// S3 client sending its requests to the mock `server`.
+pub(crate) fn s3_client(server: &MockServer) -> aws_sdk_s3::Client {
+    let config = aws_sdk_s3::config::Builder::from(&sdk_config())
+        .endpoint_url(server.url(""))
+        .force_path_style(true)
+        .build();
+    aws_sdk_s3::Client::from_conf(config)
+}
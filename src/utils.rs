@@ -1,11 +1,107 @@
+use anyhow::{Context as _, Error};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use indicatif::MultiProgress;
 use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-pub fn to_hex(sha: &Sha256) -> String {
-    let sha = sha.clone().finalize();
-    let bytes = sha.as_slice();
+/// Target of the logs reporting the progress of each file, hidden by `--quiet`.
+pub const PROGRESS_TARGET: &str = "ci_mirrors::progress";
+
+/// Progress bars of the downloads, shared with the logs so that they're printed above the bars.
+static PROGRESS_BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Show the progress bars of the downloads for the rest of the process. They're hidden unless
+/// this is called.
+pub(crate) fn enable_progress_bars() {
+    PROGRESS_BARS.get_or_init(MultiProgress::new);
+}
+
+/// Progress bars the downloads should be added to, if they're shown.
+pub(crate) fn progress_bars() -> Option<MultiProgress> {
+    PROGRESS_BARS.get().cloned()
+}
+
+/// Writer of the logs to stderr, hiding the progress bars while writing so that they don't
+/// garble the logs.
+pub(crate) struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match PROGRESS_BARS.get() {
+            Some(bars) => bars.suspend(|| std::io::stderr().write(buf)),
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Format of the reports printed by the commands.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Meant to be read by humans.
+    Text,
+    Json,
+}
+
+pub fn to_hex<D: Digest + Clone>(sha: &D) -> String {
+    bytes_to_hex(sha.clone().finalize().as_slice())
+}
+
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
     let mut result = String::new();
     for byte in bytes {
         result.push_str(&format!("{byte:0>2x}"));
     }
     result
 }
+
+/// Whether the string is a hex-encoded SHA-256 hash, in lowercase.
+pub fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Whether the string is a hex-encoded SHA-512 hash, in lowercase.
+pub fn is_sha512_hex(hash: &str) -> bool {
+    hash.len() == 128 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Format the hash as a Subresource Integrity string (`sha256-<base64>`).
+pub fn to_sri(sha: &Sha256) -> String {
+    format!("sha256-{}", BASE64.encode(sha.clone().finalize()))
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it, so that readers never see a
+/// partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents).with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("failed to rename {} to {}", tmp.display(), path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::HELLO_SHA256;
+
+    #[test]
+    fn sri_matches_hex() {
+        let mut sha = Sha256::new();
+        sha.update(b"hello");
+        let sri = to_sri(&sha);
+        assert_eq!(sri, "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+
+        let decoded = BASE64.decode(sri.strip_prefix("sha256-").unwrap()).unwrap();
+        let hex: String = decoded.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(hex, HELLO_SHA256);
+        assert_eq!(to_hex(&sha), HELLO_SHA256);
+    }
+}